@@ -1,4 +1,6 @@
-use shardingite::{ShardingIte, ShardingIteConfig, SqlParam, NO_PARAMS};
+use shardingite::{
+    KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule, ShardingStrategy, SqlParam, NO_PARAMS,
+};
 
 const INIT_SQL: &str = r#"
 CREATE TABLE IF NOT EXISTS user (
@@ -39,13 +41,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let conn = ShardingIte::new(ShardingIteConfig {
         sharding_count: SHARDING_COUNT,
         sharding_path: Box::new(|i| format!("/tmp/shardingite/{}.sqlite", i)),
-        sharding_table: "user".to_string(),
-        sharding_column: "id".to_string(),
-        sharding_index: Box::new(|param| match param {
-            SqlParam::I64(n) => Ok(*n as u32 % SHARDING_COUNT),
-            SqlParam::U32(n) => Ok(n % SHARDING_COUNT),
-            p @ _ => Err(format!("Invalid param: {:?}", p).into()),
-        }),
+        sharding_rules: vec![ShardingRule {
+            table: "user".to_string(),
+            columns: vec!["id".to_string()],
+            strategy: ShardingStrategy::Modulo,
+        }],
+        reference_tables: vec![],
+        key_update: KeyUpdate::Reject,
     })?;
 
     conn.execute_batch(INIT_SQL)?;
@@ -0,0 +1,76 @@
+use shardingite::{
+    BucketMap, KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule, ShardingStrategy,
+};
+
+const USAGE: &str = r#"Usage: reshard <path> <from-count> <to-count> <table>:<columns>:<strategy>...
+
+  <path>      Shard file path, '{}' is replaced by the shard index
+  <columns>   Key columns separated by ','
+  <strategy>  'modulo', 'hash' or 'bucket=<buckets>'
+"#;
+
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1).collect()) {
+        eprintln!("{}", e);
+        eprint!("{}", USAGE);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() < 4 {
+        return Err("Missing arguments".into());
+    }
+
+    let path = args[0].to_string();
+    let from_count: u32 = args[1].parse()?;
+    let to_count: u32 = args[2].parse()?;
+
+    let from = ShardingIte::new(config(&path, from_count, &args[3..])?)?;
+    let mut to = config(&path, to_count, &args[3..])?;
+    let report = from.reshard(&mut to)?;
+
+    println!("Moved rows: {}", report.moved_rows);
+    for (table, count) in report.table_rows {
+        println!("Table '{}': {} rows", table, count);
+    }
+
+    Ok(())
+}
+
+fn config(
+    path: &str,
+    count: u32,
+    rules: &[String],
+) -> Result<ShardingIteConfig, Box<dyn std::error::Error>> {
+    let mut sharding_rules = vec![];
+    for rule in rules {
+        let parts: Vec<&str> = rule.split(':').collect();
+        let (table, column, strategy) = match parts.as_slice() {
+            [table, column, strategy] => (table, column, strategy),
+            _ => return Err(format!("Invalid rule '{}'", rule).into()),
+        };
+        let strategy = match *strategy {
+            "modulo" => ShardingStrategy::Modulo,
+            "hash" => ShardingStrategy::Hash,
+            s => match s.strip_prefix("bucket=") {
+                Some(n) => ShardingStrategy::Bucket(BucketMap::new(n.parse()?)?),
+                None => return Err(format!("Invalid strategy '{}'", s).into()),
+            },
+        };
+        sharding_rules.push(ShardingRule {
+            table: table.to_string(),
+            columns: column.split(',').map(|c| c.to_string()).collect(),
+            strategy,
+        });
+    }
+
+    let path = path.to_string();
+    Ok(ShardingIteConfig {
+        sharding_count: count,
+        sharding_path: Box::new(move |i| path.replace("{}", &i.to_string())),
+        sharding_rules,
+        reference_tables: vec![],
+        key_update: KeyUpdate::Reject,
+    })
+}
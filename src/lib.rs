@@ -1,17 +1,24 @@
 mod parser;
+mod reshard;
 mod rewriter;
 mod router;
 mod sql_daemon;
+mod strategy;
 
+pub use parser::{Aggregate, Limit, OrderBy, Query};
+pub use reshard::ReshardReport;
 pub use rusqlite;
 pub use sql_daemon::SqlParam;
+pub use strategy::{BucketMap, ShardingIndex, ShardingStrategy};
 
-use crate::{rewriter::ReWriter, router::Router, sql_daemon::SqlDaemon};
-use parser::{Aggregate, OrderBy, Parser, Query};
-use sql_daemon::{DataCall, DataRet, SqlValue};
+use crate::{reshard::Resharder, rewriter::ReWriter, router::Router, sql_daemon::SqlDaemon};
+use parser::{ParsedSql, Parser, Upsert};
+use sql_daemon::{Collation, DataCall, DataRet, SqlRows, SqlValue};
 use std::{
-    cmp::{Ordering, Reverse},
-    collections::{BinaryHeap, HashMap},
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    panic::RefUnwindSafe,
+    rc::Rc,
     sync::{
         mpsc::{self, Receiver, Sender},
         Arc,
@@ -26,19 +33,62 @@ pub const NO_PARAMS: Vec<SqlParam> = Vec::new();
 pub struct ShardingIteConfig {
     pub sharding_count: u32,
     pub sharding_path: Box<dyn Fn(u32) -> String + Send>,
-    pub sharding_table: String,
-    pub sharding_column: String,
-    pub sharding_index: Box<dyn Fn(&SqlParam) -> Result<u32> + Send>,
+    /// Tables not listed here or in `reference_tables` are written to and read from every shard
+    pub sharding_rules: Vec<ShardingRule>,
+    /// Tables copied to every shard, writes go to all shards atomically and reads to one shard
+    pub reference_tables: Vec<String>,
+    /// Handling of UPDATEs which assign a sharding key column
+    pub key_update: KeyUpdate,
+}
+
+/// What to do with an UPDATE which assigns a sharding key column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUpdate {
+    /// Fail the update
+    Reject,
+    /// Update in place, then move the rows whose key now belongs to another shard, all in one
+    /// transaction
+    Move,
+}
+
+pub struct ShardingRule {
+    pub table: String,
+    /// Columns of the sharding key, their values are passed to the strategy in this order
+    pub columns: Vec<String>,
+    pub strategy: ShardingStrategy,
+}
+
+/// How a statement would be routed and merged, see `ShardingIte::explain`.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    /// Statement as parsed, placeholders are shown as `?NNN`
+    pub statement: String,
+    /// Shards the statement is sent to, with the sql each one runs. For an INSERT ... SELECT
+    /// into a sharding table these run the SELECT, whose rows are then inserted on the shards
+    /// of their keys
+    pub shards: Vec<(u32, String)>,
+    /// True if the shards come from a shard hint instead of the router
+    pub hinted: bool,
+    /// Merge steps applied by `Rows`, `None` for statements other than queries
+    pub query: Option<Query>,
 }
 
 pub struct ShardingIte {
     config: ShardingIteConfig,
     call_map: HashMap<u32, Sender<DataCall>>,
     ret_rx: Receiver<(u32, DataRet)>,
+    /// User collations by uppercase name
+    collations: HashMap<String, Collation>,
 }
 
 impl ShardingIte {
-    pub fn new(config: ShardingIteConfig) -> Result<Self> {
+    pub fn new(mut config: ShardingIteConfig) -> Result<Self> {
+        for rule in &config.sharding_rules {
+            rule.strategy
+                .validate()
+                .map_err(|e| format!("Invalid strategy of table '{}': {}", rule.table, e))?;
+        }
+
         let mut call_map: HashMap<u32, Sender<DataCall>> = HashMap::new();
         let (ret_tx, ret_rx) = mpsc::channel::<(u32, DataRet)>();
 
@@ -65,10 +115,19 @@ impl ShardingIte {
             call_map.insert(i, call_tx);
         }
 
+        // Load bucket maps next to the shard files
+        for rule in &mut config.sharding_rules {
+            if let ShardingStrategy::Bucket(map) = &mut rule.strategy {
+                let path = BucketMap::path_of(&config.sharding_path.as_ref()(0), &rule.table);
+                map.init(&path, config.sharding_count)?;
+            }
+        }
+
         Ok(Self {
             config,
             call_map,
             ret_rx,
+            collations: HashMap::new(),
         })
     }
 
@@ -83,7 +142,7 @@ impl ShardingIte {
     }
 
     pub fn execute_batch(&self, sql: &str) -> Result<()> {
-        for sql in sql.split(";").map(|s| s.trim()).filter(|s| s.len() > 0) {
+        for sql in sql.split(";").map(|s| s.trim()).filter(|s| !s.is_empty()) {
             self.execute(sql, NO_PARAMS)?;
         }
 
@@ -96,27 +155,117 @@ impl ShardingIte {
         Ok(tc)
     }
 
-    pub fn prepare(&self, sql: &str) -> Result<Statement> {
-        let ast = Parser::parse(sql)?;
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+        let ParsedSql {
+            ast,
+            param_names,
+            shards,
+            upsert,
+        } = Parser::parse(sql)?;
 
-        let sql = ReWriter::rewrite(&ast);
+        let distinct_on_key = Router::is_distinct_on_key(&self.config, &ast);
+        let sql = ReWriter::rewrite(&ast, upsert.as_ref(), distinct_on_key);
 
         // Send prepare
         for i in 0..self.config.sharding_count {
             self.send_data(i, DataCall::Prepare(sql.to_string()))?;
         }
 
-        // Wait prepare
-        for _ in 0..self.config.sharding_count {
-            match self.ret_rx.recv()?.1 {
-                DataRet::Prepare(ret) => ret?,
-                e @ _ => {
-                    return Err(format!("Message mismatch in wait prepare: {:?}", e).into());
+        self.wait_prepare()?;
+
+        let mut stmt = Statement::new(self, ast, param_names)?;
+        stmt.upsert = upsert;
+        if let Some(shards) = shards {
+            stmt.on_shards(&shards)?;
+        }
+
+        Ok(stmt)
+    }
+
+    /// Prepares a statement which runs on `shards` only, see `Statement::on_shards`.
+    pub fn prepare_on(&self, shards: &[u32], sql: &str) -> Result<Statement<'_>> {
+        let mut stmt = self.prepare(sql)?;
+        stmt.on_shards(shards)?;
+
+        Ok(stmt)
+    }
+
+    /// Returns the plan of `sql` with `params` without running it.
+    pub fn explain(&self, sql: &str, params: Vec<SqlParam>) -> Result<Plan> {
+        let ParsedSql {
+            ast,
+            shards,
+            upsert,
+            ..
+        } = Parser::parse(sql)?;
+
+        let distinct_on_key = Router::is_distinct_on_key(&self.config, &ast);
+        let hinted = shards.is_some();
+        let shards = match shards {
+            Some(shards) => {
+                if let Some(i) = shards.iter().find(|i| **i >= self.config.sharding_count) {
+                    return Err(format!(
+                        "Shard {} out of range, sharding count is {}",
+                        i, self.config.sharding_count
+                    )
+                    .into());
                 }
+                let sql = ReWriter::rewrite(&ast, upsert.as_ref(), distinct_on_key);
+                shards.into_iter().map(|i| (i, sql.to_string())).collect()
             }
-        }
+            None if Router::get_insert_select(&self.config, &ast).is_some() => {
+                // Rows of the SELECT are inserted on the shards of their keys once read
+                let source = match &ast {
+                    sqlparser::ast::Statement::Insert { source, .. } => source,
+                    _ => return Err("Expect insert statement".into()),
+                };
+                let sql = ReWriter::to_sql(&sqlparser::ast::Statement::Query(source.clone()));
+                Router::get_insert_select_indexes(&self.config, source, &params)?
+                    .into_iter()
+                    .map(|i| (i, sql.to_string()))
+                    .collect()
+            }
+            None => {
+                // Multi-row inserts spanning several shards are split like in `Statement::execute`
+                let mut shard_rows: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+                if let Some(rows) = Router::get_insert_rows_indexes(&self.config, &ast, &params)? {
+                    for (row, i) in rows.iter().enumerate() {
+                        shard_rows.entry(*i).or_default().push(row);
+                    }
+                }
 
-        Ok(Statement::new(self, ast)?)
+                if shard_rows.len() > 1 {
+                    let mut shards = vec![];
+                    for (i, rows) in shard_rows {
+                        shards.push((
+                            i,
+                            ReWriter::rewrite_insert_rows(&ast, upsert.as_ref(), &rows, &params)?.0,
+                        ));
+                    }
+                    shards
+                } else {
+                    let sql = ReWriter::rewrite(&ast, upsert.as_ref(), distinct_on_key);
+                    Router::get_indexes_with_params(&self.config, &ast, &params)?
+                        .into_iter()
+                        .map(|i| (i, sql.to_string()))
+                        .collect()
+                }
+            }
+        };
+
+        let query = match ast {
+            sqlparser::ast::Statement::Query(_) => {
+                Some(Parser::get_query_from_ast(&ast, distinct_on_key)?)
+            }
+            _ => None,
+        };
+
+        Ok(Plan {
+            statement: ReWriter::to_sql_with_upsert(&ast, upsert.as_ref()),
+            shards,
+            hinted,
+            query,
+        })
     }
 
     pub fn query_row<T, F>(&self, sql: &str, params: Vec<SqlParam>, f: F) -> Result<T>
@@ -143,6 +292,75 @@ impl ShardingIte {
         max
     }
 
+    /// Registers the collation `name` on every shard, the merge of ordered rows uses it too.
+    pub fn create_collation<F>(&mut self, name: &str, f: F) -> Result<()>
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + RefUnwindSafe + 'static,
+    {
+        let collation = Collation::new(f);
+        for i in 0..self.config.sharding_count {
+            self.send_data(
+                i,
+                DataCall::CreateCollation(name.to_string(), collation.clone()),
+            )?;
+        }
+
+        let mut error = None;
+        for _ in 0..self.config.sharding_count {
+            match self.ret_rx.recv()? {
+                (_, DataRet::CreateCollation(Ok(()))) => {}
+                (i, DataRet::CreateCollation(Err(e))) => {
+                    error = Some(format!("Create collation on shard {} error: {}", i, e))
+                }
+                (_, m) => error = Some(format!("Message mismatch in create_collation: {:?}", m)),
+            }
+        }
+        if let Some(e) = error {
+            return Err(e.into());
+        }
+
+        self.collations.insert(name.to_uppercase(), collation);
+
+        Ok(())
+    }
+
+    /// Moves the rows of the sharding tables of `config` to their shards in `config`, copies the
+    /// schema to new shard files and checks row counts. Consumes the shard set, which would
+    /// route by the old config, every shard file is locked meanwhile. Open the new shard set
+    /// with `ShardingIte::new(config)` afterwards.
+    ///
+    /// An interrupted reshard can be run again with the same configs.
+    pub fn reshard(self, config: &mut ShardingIteConfig) -> Result<ReshardReport> {
+        Resharder::run(&self.config, config)
+    }
+
+    /// Waits the prepare of every shard, if one fails the statements prepared on the others
+    /// are ended so no reply is left behind.
+    fn wait_prepare(&self) -> Result<()> {
+        let mut prepared = vec![];
+        let mut error: Option<Box<dyn std::error::Error>> = None;
+        for _ in 0..self.config.sharding_count {
+            match self.ret_rx.recv()? {
+                (i, DataRet::Prepare(Ok(()))) => prepared.push(i),
+                (_, DataRet::Prepare(Err(e))) => error = error.or_else(|| Some(e.into())),
+                (_, e) => {
+                    error = error.or_else(|| {
+                        Some(format!("Message mismatch in wait prepare: {:?}", e).into())
+                    })
+                }
+            }
+        }
+
+        if let Some(e) = error {
+            for i in prepared {
+                self.send_data(i, DataCall::StatementEnd).ok();
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
     fn send_data(&self, index: u32, data: DataCall) -> Result<()> {
         let call_tx = self
             .call_map
@@ -159,10 +377,10 @@ impl Default for ShardingIte {
     fn default() -> Self {
         Self::new(ShardingIteConfig {
             sharding_count: 0,
-            sharding_table: "".to_string(),
-            sharding_column: "".to_string(),
             sharding_path: Box::new(|_| unreachable!()),
-            sharding_index: Box::new(|_| unreachable!()),
+            sharding_rules: vec![],
+            reference_tables: vec![],
+            key_update: KeyUpdate::Reject,
         })
         .unwrap()
     }
@@ -193,7 +411,7 @@ impl<'a> Transaction<'a> {
         for _ in 0..si.config.sharding_count {
             match si.ret_rx.recv()?.1 {
                 DataRet::Transaction(ret) => ret?,
-                e @ _ => {
+                e => {
                     return Err(format!("Message mismatch in wait transaction: {:?}", e).into());
                 }
             }
@@ -206,7 +424,15 @@ impl<'a> Transaction<'a> {
     }
 
     pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
-        let ast = Parser::parse(sql)?;
+        let ParsedSql {
+            ast,
+            param_names,
+            shards,
+            upsert,
+        } = Parser::parse(sql)?;
+
+        let distinct_on_key = Router::is_distinct_on_key(&self.sharding_ite.config, &ast);
+        let sql = ReWriter::rewrite(&ast, upsert.as_ref(), distinct_on_key);
 
         // Start prepare
         for i in 0..self.sharding_ite.config.sharding_count {
@@ -214,19 +440,24 @@ impl<'a> Transaction<'a> {
                 .send_data(i, DataCall::Prepare(sql.to_string()))?;
         }
 
-        // Wait prepare
-        for _ in 0..self.sharding_ite.config.sharding_count {
-            match self.sharding_ite.ret_rx.recv()?.1 {
-                DataRet::Prepare(ret) => ret?,
-                e @ _ => {
-                    return Err(
-                        format!("Message mismatch in wait transaction prepare: {:?}", e).into(),
-                    );
-                }
-            }
+        self.sharding_ite.wait_prepare()?;
+
+        let mut stmt = Statement::new(self.sharding_ite, ast, param_names)?;
+        stmt.in_transaction = true;
+        stmt.upsert = upsert;
+        if let Some(shards) = shards {
+            stmt.on_shards(&shards)?;
         }
 
-        Ok(Statement::new(self.sharding_ite, ast)?)
+        Ok(stmt)
+    }
+
+    /// Prepares a statement which runs on `shards` only, see `Statement::on_shards`.
+    pub fn prepare_on(&self, shards: &[u32], sql: &str) -> Result<Statement<'_>> {
+        let mut stmt = self.prepare(sql)?;
+        stmt.on_shards(shards)?;
+
+        Ok(stmt)
     }
 
     pub fn commit(mut self) -> Result<()> {
@@ -242,7 +473,7 @@ impl<'a> Transaction<'a> {
         for _ in 0..self.sharding_ite.config.sharding_count {
             match self.sharding_ite.ret_rx.recv()?.1 {
                 DataRet::TransactionCommit(ret) => ret?,
-                e @ _ => {
+                e => {
                     return Err(
                         format!("Message mismatch in wait transaction commit: {:?}", e).into(),
                     );
@@ -264,7 +495,7 @@ impl<'a> Transaction<'a> {
         for _ in 0..self.sharding_ite.config.sharding_count {
             match self.sharding_ite.ret_rx.recv()?.1 {
                 DataRet::TransactionRollback(ret) => ret?,
-                e @ _ => {
+                e => {
                     return Err(
                         format!("Message mismatch in wait transaction rollback: {:?}", e).into(),
                     );
@@ -298,20 +529,129 @@ impl Drop for Transaction<'_> {
 pub struct Statement<'a> {
     sharding_ite: &'a ShardingIte,
     ast: sqlparser::ast::Statement,
+    param_names: Vec<Option<String>>,
+    shards: Option<Vec<u32>>,
+    upsert: Option<Upsert>,
+    in_transaction: bool,
     exec_counter: usize,
 }
 
 impl<'a> Statement<'a> {
-    pub fn new(sdi: &'a ShardingIte, ast: sqlparser::ast::Statement) -> Result<Self> {
+    pub fn new(
+        sdi: &'a ShardingIte,
+        ast: sqlparser::ast::Statement,
+        param_names: Vec<Option<String>>,
+    ) -> Result<Self> {
         Ok(Self {
             sharding_ite: sdi,
             ast,
+            param_names,
+            shards: None,
+            upsert: None,
+            in_transaction: false,
             exec_counter: 0,
         })
     }
 
+    /// Runs the statement on `shards` only instead of the shards picked by the router.
+    pub fn on_shards(&mut self, shards: &[u32]) -> Result<&mut Self> {
+        if shards.is_empty() {
+            return Err("Expect at least one shard".into());
+        }
+        if let Some(i) = shards
+            .iter()
+            .find(|i| **i >= self.sharding_ite.config.sharding_count)
+        {
+            return Err(format!(
+                "Shard {} out of range, sharding count is {}",
+                i, self.sharding_ite.config.sharding_count
+            )
+            .into());
+        }
+
+        let mut shards = shards.to_vec();
+        shards.sort_unstable();
+        shards.dedup();
+        self.shards = Some(shards);
+
+        Ok(self)
+    }
+
+    /// Executes with named parameters such as `(":name", value)`, names include the prefix.
+    pub fn execute_named(&mut self, params: Vec<(&str, SqlParam)>) -> Result<()> {
+        let params = self.named_params_to_vec(params)?;
+        self.execute(params)
+    }
+
+    /// Queries with named parameters such as `(":name", value)`, names include the prefix.
+    pub fn query_named(&mut self, params: Vec<(&str, SqlParam)>) -> Result<Rows<'_>> {
+        let params = self.named_params_to_vec(params)?;
+        self.query(params)
+    }
+
     pub fn execute(&mut self, params: Vec<SqlParam>) -> Result<()> {
-        let list = Router::get_indexes_with_params(&self.sharding_ite.config, &self.ast, &params)?;
+        let sharding_ite = self.sharding_ite;
+        if let Some(rule) =
+            Router::get_upsert_key_update(&sharding_ite.config, &self.ast, self.upsert.as_ref())
+        {
+            return Err(format!(
+                "Update of sharding key ({}) of table '{}' in ON CONFLICT is rejected",
+                rule.columns.join(", "),
+                rule.table
+            )
+            .into());
+        }
+        if self.shards.is_none() {
+            if let Some(rule) = Router::get_insert_select(&sharding_ite.config, &self.ast) {
+                return self.execute_insert_select(rule, params);
+            }
+        }
+
+        // Split multi-row insert which spans several shards
+        let rows = match self.shards {
+            Some(_) => None,
+            None => Router::get_insert_rows_indexes(&self.sharding_ite.config, &self.ast, &params)?,
+        };
+        if let Some(rows) = rows {
+            let mut shard_rows: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+            for (row, i) in rows.iter().enumerate() {
+                shard_rows.entry(*i).or_default().push(row);
+            }
+
+            if shard_rows.len() > 1 {
+                for (i, rows) in &shard_rows {
+                    let (sql, params) = ReWriter::rewrite_insert_rows(
+                        &self.ast,
+                        self.upsert.as_ref(),
+                        rows,
+                        &params,
+                    )?;
+                    self.sharding_ite
+                        .send_data(*i, DataCall::StatementExecuteSql(sql, Arc::new(params)))?;
+                    self.exec_counter += 1;
+                }
+
+                return Ok(());
+            }
+        }
+
+        let list = self.get_routes(&params)?;
+
+        if let Some(rule) = Router::get_key_update(&sharding_ite.config, &self.ast) {
+            return match sharding_ite.config.key_update {
+                KeyUpdate::Reject => Err(format!(
+                    "Update of sharding key ({}) of table '{}' is rejected",
+                    rule.columns.join(", "),
+                    rule.table
+                )
+                .into()),
+                KeyUpdate::Move => self.execute_key_update(&list, rule, params),
+            };
+        }
+
+        if Router::is_reference_write(&self.sharding_ite.config, &self.ast) {
+            return self.execute_atomic(&list, params);
+        }
 
         let params = Arc::new(params);
 
@@ -325,10 +665,14 @@ impl<'a> Statement<'a> {
         Ok(())
     }
 
-    pub fn query(&mut self, params: Vec<SqlParam>) -> Result<Rows> {
-        let list = Router::get_indexes_with_params(&self.sharding_ite.config, &self.ast, &params)?;
+    pub fn query(&mut self, params: Vec<SqlParam>) -> Result<Rows<'_>> {
+        let list = self.get_routes(&params)?;
         let params = Arc::new(params);
-        let query = Parser::get_query_from_ast(&self.ast)?;
+        let distinct_on_key = Router::is_distinct_on_key(&self.sharding_ite.config, &self.ast);
+        let mut query = Parser::get_query_from_ast(&self.ast, distinct_on_key)?;
+        if list.len() > 1 {
+            self.resolve_declared_collations(&list, &mut query)?;
+        }
 
         // Send query
         for i in &list {
@@ -340,13 +684,13 @@ impl<'a> Statement<'a> {
         for _ in &list {
             match self.sharding_ite.ret_rx.recv()?.1 {
                 DataRet::StatementQuery(ret) => ret?,
-                e @ _ => {
+                e => {
                     return Err(format!("Message mismatch in wait statement query: {:?}", e).into());
                 }
             }
         }
 
-        Ok(Rows::new(self.sharding_ite, list, query)?)
+        Rows::new(self.sharding_ite, list, query)
     }
 
     pub fn query_row<T, F>(&mut self, params: Vec<SqlParam>, f: F) -> Result<T>
@@ -357,7 +701,7 @@ impl<'a> Statement<'a> {
             return f(row);
         }
 
-        return Err("Query is empty".into());
+        Err("Query is empty".into())
     }
 
     pub fn query_map<F, T>(&mut self, params: Vec<SqlParam>, map: F) -> Result<MappedRows<'_, F>>
@@ -369,15 +713,377 @@ impl<'a> Statement<'a> {
     }
 }
 
-impl<'a> Drop for Statement<'a> {
-    fn drop(&mut self) {
-        // Wait all exec are consumed
+impl<'a> Statement<'a> {
+    /// Executes on every shard of `list` and waits for the results. Outside of a transaction
+    /// each shard runs in its own transaction, which is only committed if all shards succeed.
+    fn execute_atomic(&mut self, list: &[u32], params: Vec<SqlParam>) -> Result<()> {
+        let params = Arc::new(params);
+        self.atomic(list, |stmt| {
+            stmt.execute_and_wait(list, || DataCall::StatementExecute(params.clone()))
+        })
+    }
+
+    /// Runs the SELECT of an INSERT ... SELECT on its shards and inserts every row into the shard
+    /// of its key, all in one transaction.
+    fn execute_insert_select(&mut self, rule: &ShardingRule, params: Vec<SqlParam>) -> Result<()> {
+        let (columns, source) = match &self.ast {
+            sqlparser::ast::Statement::Insert {
+                columns, source, ..
+            } => (columns.clone(), source.clone()),
+            _ => return Err("Expect insert statement".into()),
+        };
+        if columns.is_empty() {
+            return Err("INSERT ... SELECT into a sharding table needs a column list".into());
+        }
+        let positions = rule
+            .columns
+            .iter()
+            .map(|c| {
+                Router::get_column_position(&columns, c)
+                    .ok_or_else(|| format!("Sharding column '{}' not found in insert", c).into())
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        let config = &self.sharding_ite.config;
+        let list = Router::get_insert_select_indexes(config, &source, &params)?;
+        let select = sqlparser::ast::Statement::Query(source.clone());
+        let select_sql = ReWriter::to_sql(&select);
+        let params = Arc::new(params);
+        let count = config.sharding_count;
+        let all: Vec<u32> = (0..count).collect();
+
+        self.atomic(&all, |stmt| {
+            let mut shard_rows: BTreeMap<u32, Vec<SqlParam>> = BTreeMap::new();
+            for (_, (_, rows)) in stmt.query_sql_and_wait(&list, &select_sql, &params)? {
+                for row in rows {
+                    if row.len() != columns.len() {
+                        return Err(format!(
+                            "SELECT returns {} columns, insert expects {}",
+                            row.len(),
+                            columns.len()
+                        )
+                        .into());
+                    }
+
+                    let row: Vec<SqlParam> = row.into_iter().map(SqlParam::from).collect();
+                    let key: Vec<SqlParam> = positions.iter().map(|p| row[*p].clone()).collect();
+                    let target = rule.strategy.index(&key, count)?;
+                    shard_rows.entry(target).or_default().extend(row);
+                }
+            }
+
+            // Stay below the default limit of 999 variables per statement
+            let chunk =
+                (999usize.saturating_sub(params.len()) / columns.len()).max(1) * columns.len();
+            for (i, values) in shard_rows {
+                for values in values.chunks(chunk) {
+                    let (sql, mut new_params) = ReWriter::rewrite_insert_values(
+                        &stmt.ast,
+                        stmt.upsert.as_ref(),
+                        values.len() / columns.len(),
+                        columns.len(),
+                        &params,
+                    )?;
+                    new_params.extend_from_slice(values);
+                    let values = Arc::new(new_params);
+                    stmt.execute_and_wait(&[i], || {
+                        DataCall::StatementExecuteSql(sql.to_string(), values.clone())
+                    })?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Runs the update, then moves the updated rows whose new key belongs to another shard.
+    ///
+    /// Updated rows are taken from `RETURNING` as the update may change the rowid, when the key
+    /// is an `INTEGER PRIMARY KEY`. Rows of `WITHOUT ROWID` tables are deleted by all of their
+    /// values, which include the primary key.
+    fn execute_key_update(
+        &mut self,
+        list: &[u32],
+        rule: &ShardingRule,
+        params: Vec<SqlParam>,
+    ) -> Result<()> {
+        let table_name = match &self.ast {
+            sqlparser::ast::Statement::Update { table_name, .. } => table_name.clone(),
+            _ => return Err("Expect update statement".into()),
+        };
+        let update_sql = ReWriter::to_sql(&self.ast);
+        let params = Arc::new(params);
+        let count = self.sharding_ite.config.sharding_count;
+        let all: Vec<u32> = (0..count).collect();
+
+        self.atomic(&all, |stmt| {
+            let probe = format!("SELECT rowid FROM {} LIMIT 0", table_name);
+            let has_rowid = stmt
+                .query_sql_and_wait(list, &probe, &Arc::new(NO_PARAMS))
+                .is_ok();
+            let (returning, first) = match has_rowid {
+                true => ("rowid, *", 1),
+                false => ("*", 0),
+            };
+            let sql = format!("{} RETURNING {}", update_sql, returning);
+            let updated = stmt.query_sql_and_wait(list, &sql, &params)?;
+
+            for (i, (columns, rows)) in updated {
+                if rows.is_empty() {
+                    continue;
+                }
+
+                let quoted: Vec<String> = columns[first..]
+                    .iter()
+                    .map(|c| format!("\"{}\"", c.replace('"', "\"\"")))
+                    .collect();
+                let positions = rule
+                    .columns
+                    .iter()
+                    .map(|c| {
+                        columns
+                            .iter()
+                            .skip(first)
+                            .position(|n| Router::is_same_name(n, c))
+                            .map(|p| p + first)
+                            .ok_or_else(|| format!("Sharding column '{}' not found", c).into())
+                    })
+                    .collect::<Result<Vec<usize>>>()?;
+                let insert = format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    table_name,
+                    quoted.join(", "),
+                    (1..=quoted.len())
+                        .map(|n| format!("?{}", n))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+                let delete = match has_rowid {
+                    true => format!("DELETE FROM {} WHERE rowid = ?1", table_name),
+                    false => format!(
+                        "DELETE FROM {} WHERE {}",
+                        table_name,
+                        quoted
+                            .iter()
+                            .enumerate()
+                            .map(|(n, c)| format!("{} IS ?{}", c, n + 1))
+                            .collect::<Vec<String>>()
+                            .join(" AND ")
+                    ),
+                };
+
+                for row in rows {
+                    let key: Vec<SqlParam> =
+                        positions.iter().map(|p| row[*p].clone().into()).collect();
+                    let target = rule.strategy.index(&key, count)?;
+                    if target == i {
+                        continue;
+                    }
+
+                    log::trace!("Move row {:?} from shard {} to {}", row, i, target);
+                    let values: Arc<Vec<SqlParam>> =
+                        Arc::new(row[first..].iter().cloned().map(SqlParam::from).collect());
+                    stmt.execute_and_wait(&[target], || {
+                        DataCall::StatementExecuteSql(insert.to_string(), values.clone())
+                    })?;
+                    let key = match has_rowid {
+                        true => Arc::new(vec![SqlParam::from(row[0].clone())]),
+                        false => values.clone(),
+                    };
+                    stmt.execute_and_wait(&[i], || {
+                        DataCall::StatementExecuteSql(delete.to_string(), key.clone())
+                    })?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Runs `f` in a transaction over the shards of `list`, unless the statement already is in
+    /// one.
+    fn atomic<F>(&mut self, list: &[u32], f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        self.wait_executes();
+
+        if self.in_transaction {
+            return f(self);
+        }
+
+        let ret = self
+            .execute_sql_and_wait(list, "BEGIN")
+            .and_then(|_| f(self));
+
+        match ret {
+            Ok(_) => self.execute_sql_and_wait(list, "COMMIT"),
+            Err(e) => {
+                self.execute_sql_and_wait(list, "ROLLBACK").ok();
+                Err(e)
+            }
+        }
+    }
+
+    fn execute_sql_and_wait(&self, list: &[u32], sql: &str) -> Result<()> {
+        let params = Arc::new(NO_PARAMS);
+        self.execute_and_wait(list, || {
+            DataCall::StatementExecuteSql(sql.to_string(), params.clone())
+        })
+    }
+
+    /// Sends the execute `call` to every shard of `list`, returns the first error.
+    fn execute_and_wait<F>(&self, list: &[u32], call: F) -> Result<()>
+    where
+        F: Fn() -> DataCall,
+    {
+        for i in list {
+            self.sharding_ite.send_data(*i, call())?;
+        }
+
+        let mut ret = Ok(());
+        for _ in list {
+            match self.sharding_ite.ret_rx.recv()? {
+                (_, DataRet::StatementExecute(Ok(_))) => {}
+                (i, DataRet::StatementExecute(Err(e))) => {
+                    if ret.is_ok() {
+                        ret = Err(format!("[{}] Statement execute error: {}", i, e).into());
+                    }
+                }
+                (_, e) => {
+                    return Err(format!("Message mismatch in wait execute: {:?}", e).into());
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Sets the collation of ORDER BY columns without an explicit one to the collation declared
+    /// in the table schema, read from the first shard of `list`.
+    fn resolve_declared_collations(&self, list: &[u32], query: &mut Query) -> Result<()> {
+        let mut schemas: HashMap<String, Option<String>> = HashMap::new();
+        for order_by in &mut query.order_by {
+            let (table, column) = match &order_by.column {
+                Some(column) => column,
+                None => continue,
+            };
+
+            let key = table.to_uppercase();
+            if !schemas.contains_key(&key) {
+                let rows = self.query_sql_and_wait(
+                    &list[..1],
+                    "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE",
+                    &Arc::new(vec![SqlParam::String(table.clone())]),
+                )?;
+                let sql = rows
+                    .into_iter()
+                    .flat_map(|(_, (_, rows))| rows)
+                    .find_map(|mut row| match row.pop() {
+                        Some(SqlValue::Text(sql)) => String::from_utf8(sql).ok(),
+                        _ => None,
+                    });
+                schemas.insert(key.clone(), sql);
+            }
+
+            if let Some(sql) = &schemas[&key] {
+                order_by.collation = Parser::get_declared_collation(sql, column)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the ad-hoc query `sql` to every shard of `list`, returns the rows of each shard or
+    /// the first error.
+    fn query_sql_and_wait(
+        &self,
+        list: &[u32],
+        sql: &str,
+        params: &Arc<Vec<SqlParam>>,
+    ) -> Result<BTreeMap<u32, SqlRows>> {
+        for i in list {
+            self.sharding_ite.send_data(
+                *i,
+                DataCall::StatementQuerySql(sql.to_string(), params.clone()),
+            )?;
+        }
+
+        let mut ret = Ok(BTreeMap::new());
+        for _ in list {
+            match self.sharding_ite.ret_rx.recv()? {
+                (i, DataRet::StatementQuerySql(Ok(rows))) => {
+                    if let Ok(map) = &mut ret {
+                        map.insert(i, rows);
+                    }
+                }
+                (i, DataRet::StatementQuerySql(Err(e))) => {
+                    if ret.is_ok() {
+                        ret = Err(format!("[{}] Statement query error: {}", i, e).into());
+                    }
+                }
+                (_, e) => {
+                    return Err(format!("Message mismatch in wait query: {:?}", e).into());
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Waits for the results of asynchronous executes, errors are only logged.
+    fn wait_executes(&mut self) {
         for _ in 0..self.exec_counter {
-            if self.sharding_ite.ret_rx.recv().is_err() {
-                break;
+            match self.sharding_ite.ret_rx.recv() {
+                Ok((i, DataRet::StatementExecute(Err(e)))) => {
+                    log::error!("[{}] Statement execute error: {}", i, e);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        self.exec_counter = 0;
+    }
+
+    fn get_routes(&self, params: &[SqlParam]) -> Result<Vec<u32>> {
+        match &self.shards {
+            Some(shards) => {
+                log::trace!("Explicit shards: {:?}", shards);
+                Ok(shards.clone())
             }
+            None => Router::get_indexes_with_params(&self.sharding_ite.config, &self.ast, params),
+        }
+    }
+
+    fn named_params_to_vec(&self, params: Vec<(&str, SqlParam)>) -> Result<Vec<SqlParam>> {
+        let mut values: Vec<Option<SqlParam>> = vec![None; self.param_names.len()];
+        for (name, param) in params {
+            let index = self
+                .param_names
+                .iter()
+                .position(|n| n.as_deref() == Some(name))
+                .ok_or(format!("Invalid parameter name: {}", name))?;
+            values[index] = Some(param);
         }
 
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value.ok_or_else(|| match &self.param_names[index] {
+                    Some(name) => format!("Parameter {} is not bound", name).into(),
+                    None => format!("Parameter ?{} can not be bound by name", index + 1).into(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl<'a> Drop for Statement<'a> {
+    fn drop(&mut self) {
+        // Wait all exec are consumed
+        self.wait_executes();
+
         // Send end
         for i in 0..self.sharding_ite.config.sharding_count {
             self.sharding_ite.send_data(i, DataCall::StatementEnd).ok();
@@ -385,12 +1091,91 @@ impl<'a> Drop for Statement<'a> {
     }
 }
 
+/// Text comparison of a sort key, other values compare like `SqlValue::sqlite_cmp`.
+#[derive(Debug)]
+enum KeyCollation {
+    Binary,
+    NoCase,
+    RTrim,
+    User(Collation),
+}
+
+impl KeyCollation {
+    fn resolve(name: Option<&str>, collations: &HashMap<String, Collation>) -> Result<Self> {
+        let name = match name {
+            Some(name) => name.to_uppercase(),
+            None => return Ok(Self::Binary),
+        };
+
+        Ok(match name.as_str() {
+            "BINARY" => Self::Binary,
+            "NOCASE" => Self::NoCase,
+            "RTRIM" => Self::RTrim,
+            _ => Self::User(
+                collations
+                    .get(&name)
+                    .cloned()
+                    .ok_or_else(|| format!("No such collation sequence: {}", name))?,
+            ),
+        })
+    }
+
+    fn compare(&self, a: &SqlValue, b: &SqlValue) -> Ordering {
+        match (self, a, b) {
+            (Self::NoCase, SqlValue::Text(a), SqlValue::Text(b)) => a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase)),
+            (Self::RTrim, SqlValue::Text(a), SqlValue::Text(b)) => {
+                let trim = |v: &[u8]| v.len() - v.iter().rev().take_while(|c| **c == b' ').count();
+                a[..trim(a)].cmp(&b[..trim(b)])
+            }
+            (Self::User(collation), SqlValue::Text(a), SqlValue::Text(b)) => {
+                collation.compare(&String::from_utf8_lossy(a), &String::from_utf8_lossy(b))
+            }
+            _ => a.sqlite_cmp(b),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SortKey {
+    order_by: OrderBy,
+    collation: KeyCollation,
+}
+
+impl SortKey {
+    fn compare(&self, a: &SqlValue, b: &SqlValue) -> Ordering {
+        let (a_null, b_null) = (matches!(a, SqlValue::Null), matches!(b, SqlValue::Null));
+        match self.order_by.nulls_first {
+            // Explicit NULLS FIRST/LAST does not depend on the direction
+            Some(first) if a_null != b_null => match a_null == first {
+                true => Ordering::Less,
+                false => Ordering::Greater,
+            },
+            _ => {
+                let ordering = self.collation.compare(a, b);
+                match self.order_by.is_asc {
+                    true => ordering,
+                    false => ordering.reverse(),
+                }
+            }
+        }
+    }
+}
+
+/// Next row of a shard in the merge, the heap pops the row which sorts first.
 #[derive(Debug)]
-struct HeapData(u32, i64, Vec<SqlValue>); // (index, column, data)
+struct HeapData {
+    index: u32,
+    keys: Vec<SqlValue>,
+    data: Vec<SqlValue>,
+    sort_keys: Rc<Vec<SortKey>>,
+}
 
 impl PartialEq for HeapData {
     fn eq(&self, other: &Self) -> bool {
-        self.1.eq(&other.1)
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -398,13 +1183,21 @@ impl Eq for HeapData {}
 
 impl PartialOrd for HeapData {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.1.partial_cmp(&other.1)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for HeapData {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.1.cmp(&other.1)
+        for ((a, b), key) in self.keys.iter().zip(&other.keys).zip(self.sort_keys.iter()) {
+            // The heap pops the greatest, which must be the row that sorts first
+            let ordering = key.compare(a, b).reverse();
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        Ordering::Equal
     }
 }
 
@@ -414,8 +1207,8 @@ pub struct Rows<'a> {
     query: Query,
     counter: u32,
     skipped: bool,
-    max_heap: Option<BinaryHeap<HeapData>>,
-    min_heap: Option<BinaryHeap<Reverse<HeapData>>>,
+    heap: Option<BinaryHeap<HeapData>>,
+    aggregated: bool,
 }
 
 impl<'a> Rows<'a> {
@@ -426,11 +1219,12 @@ impl<'a> Rows<'a> {
             query,
             counter: 0,
             skipped: false,
-            max_heap: None,
-            min_heap: None,
+            heap: None,
+            aggregated: false,
         })
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<Option<Row>> {
         // Check limit
         if let Some(limit) = &self.query.limit {
@@ -448,114 +1242,153 @@ impl<'a> Rows<'a> {
             self.skipped = true;
         }
 
-        if let Some(aggregate) = self.query.aggregate.clone() {
-            match aggregate {
-                Aggregate::Count(col) => {
-                    if col.as_str() != "*" {
-                        return Err(
-                            "Currently only supports * with count function in query function"
-                                .into(),
-                        );
-                    }
+        let next = self._next()?;
+        self.counter += 1;
 
-                    let mut sum: i64 = 0;
-                    while let Some(row) = self._next()? {
-                        sum += row.get::<i64>(0)?;
-                    }
-
-                    if sum == 0 {
-                        Ok(None)
-                    } else {
-                        Ok(Some(Row::new(vec![SqlValue::Integer(sum)])))
-                    }
-                }
-            }
-        } else {
-            let next = self._next()?;
-            self.counter += 1;
-
-            Ok(next)
-        }
+        Ok(next)
     }
 
     fn _next(&mut self) -> Result<Option<Row>> {
-        let next = if let Some(order_by) = self.query.order_by.clone() {
-            self.next_with_order(order_by)?
+        if !self.query.aggregates.is_empty() {
+            return Ok(self.next_aggregate()?.map(Row::new));
+        }
+
+        let next = if !self.query.order_by.is_empty() {
+            self.next_with_order()?
         } else {
             self.next_without_order()?
         };
 
-        Ok(next.map(|v| Row::new(v)))
+        // Strip hidden sort columns
+        let hidden = self.query.hidden_columns as usize;
+        Ok(next.map(|mut v| {
+            v.truncate(v.len().saturating_sub(hidden));
+            Row::new(v)
+        }))
     }
 
-    fn next_without_order(&self) -> Result<Option<Vec<SqlValue>>> {
-        for i in &self.sharding_index_list {
-            loop {
-                self.sharding_ite.send_data(*i, DataCall::RowsNext)?;
-                match self.sharding_ite.ret_rx.recv()?.1 {
-                    DataRet::Next(v) => match v {
-                        Ok(v) => {
-                            if let Some(v) = v {
-                                return Ok(Some(v));
-                            } else {
-                                break;
-                            }
-                        }
-                        Err(e) => return Err(e.into()),
-                    },
-                    m @ _ => {
-                        return Err(
-                            format!("Message mismatch in wait next_without_order: {:?}", m).into(),
-                        )
-                    }
+    /// Merges the aggregate row of every shard, an aggregate query returns exactly one row.
+    fn next_aggregate(&mut self) -> Result<Option<Vec<SqlValue>>> {
+        if self.aggregated {
+            return Ok(None);
+        }
+        self.aggregated = true;
+
+        if let [Aggregate::CountDistinct(_)] = self.query.aggregates.as_slice() {
+            // Shards return their distinct values, which may repeat across shards
+            let mut values = vec![];
+            while let Some(row) = self.next_without_order()? {
+                match row.into_iter().next() {
+                    Some(SqlValue::Null) | None => {}
+                    Some(value) => values.push(value),
                 }
             }
+            values.sort_by(SqlValue::sqlite_cmp);
+            values.dedup_by(|a, b| a.sqlite_cmp(b) == Ordering::Equal);
+
+            return Ok(Some(vec![SqlValue::Integer(values.len() as i64)]));
         }
 
-        Ok(None)
-    }
+        // Merge the columns queried on the shards, then combine them per aggregate
+        let columns: Vec<Aggregate> = self
+            .query
+            .aggregates
+            .iter()
+            .flat_map(Aggregate::shard_aggregates)
+            .collect();
+        let mut values: Vec<SqlValue> = columns
+            .iter()
+            .map(|a| match a {
+                Aggregate::Count(_) => SqlValue::Integer(0),
+                Aggregate::Total(_) => SqlValue::Real(0.0),
+                _ => SqlValue::Null,
+            })
+            .collect();
+        while let Some(row) = self.next_without_order()? {
+            for (i, column) in columns.iter().enumerate() {
+                let value = row
+                    .get(i)
+                    .cloned()
+                    .ok_or_else(|| format!("Aggregate column {} not found", i))?;
+                let acc = std::mem::replace(&mut values[i], SqlValue::Null);
+                values[i] = merge_aggregate(column, acc, value)?;
+            }
+        }
 
-    fn next_with_order(&mut self, order_by: OrderBy) -> Result<Option<Vec<SqlValue>>> {
-        if order_by.is_asc {
-            // Use min-heap
-            if self.min_heap.is_none() {
-                // Init heap
-                let mut heap = BinaryHeap::new();
-                for i in &self.sharding_index_list {
-                    if let Some(data) = self.next_heap_data(*i, &order_by)? {
-                        heap.push(Reverse(data));
-                    }
+        let mut values = values.into_iter();
+        let mut row = Vec::with_capacity(self.query.aggregates.len());
+        for aggregate in &self.query.aggregates {
+            match aggregate {
+                Aggregate::Avg(_) => {
+                    let (total, count) = (values.next(), values.next());
+                    row.push(match (total, count) {
+                        (_, Some(SqlValue::Integer(0))) => SqlValue::Null,
+                        (Some(total), Some(SqlValue::Integer(count))) => {
+                            SqlValue::Real(as_real(&total)? / count as f64)
+                        }
+                        v => return Err(format!("Invalid average columns: {:?}", v).into()),
+                    });
                 }
-                self.min_heap = Some(heap);
+                _ => row.push(values.next().ok_or("Aggregate column not found")?),
             }
+        }
 
-            if let Some(data) = self.min_heap.as_mut().unwrap().pop() {
-                if let Some(data) = self.next_heap_data(data.0 .0, &order_by)? {
-                    self.min_heap.as_mut().unwrap().push(Reverse(data));
-                }
+        Ok(Some(row))
+    }
 
-                return Ok(Some(data.0 .2));
-            }
-        } else {
-            // Use max-heap
-            if self.max_heap.is_none() {
-                // Init heap
-                let mut heap = BinaryHeap::new();
-                for i in &self.sharding_index_list {
-                    if let Some(data) = self.next_heap_data(*i, &order_by)? {
-                        heap.push(data);
+    fn next_without_order(&self) -> Result<Option<Vec<SqlValue>>> {
+        for i in &self.sharding_index_list {
+            self.sharding_ite.send_data(*i, DataCall::RowsNext)?;
+            match self.sharding_ite.ret_rx.recv()?.1 {
+                DataRet::Next(v) => {
+                    if let Some(v) = v? {
+                        return Ok(Some(v));
                     }
                 }
-                self.max_heap = Some(heap);
+                m => {
+                    return Err(
+                        format!("Message mismatch in wait next_without_order: {:?}", m).into(),
+                    )
+                }
             }
+        }
+
+        Ok(None)
+    }
 
-            if let Some(data) = self.max_heap.as_mut().unwrap().pop() {
-                if let Some(data) = self.next_heap_data(data.0, &order_by)? {
-                    self.max_heap.as_mut().unwrap().push(data);
+    fn next_with_order(&mut self) -> Result<Option<Vec<SqlValue>>> {
+        if self.heap.is_none() {
+            // Init heap
+            let sort_keys = self
+                .query
+                .order_by
+                .iter()
+                .map(|o| {
+                    Ok(SortKey {
+                        order_by: o.clone(),
+                        collation: KeyCollation::resolve(
+                            o.collation.as_deref(),
+                            &self.sharding_ite.collations,
+                        )?,
+                    })
+                })
+                .collect::<Result<Vec<SortKey>>>()?;
+            let sort_keys = Rc::new(sort_keys);
+            let mut heap = BinaryHeap::new();
+            for i in &self.sharding_index_list {
+                if let Some(data) = self.next_heap_data(*i, &sort_keys)? {
+                    heap.push(data);
                 }
+            }
+            self.heap = Some(heap);
+        }
 
-                return Ok(Some(data.2));
+        if let Some(data) = self.heap.as_mut().unwrap().pop() {
+            if let Some(next) = self.next_heap_data(data.index, &data.sort_keys)? {
+                self.heap.as_mut().unwrap().push(next);
             }
+
+            return Ok(Some(data.data));
         }
 
         Ok(None)
@@ -567,34 +1400,86 @@ impl<'a> Rows<'a> {
             DataRet::Next(v) => match v {
                 Ok(v) => {
                     if let Some(v) = v {
-                        return Ok(Some(v));
+                        Ok(Some(v))
                     } else {
-                        return Ok(None);
+                        Ok(None)
                     }
                 }
-                Err(e) => return Err(e.into()),
+                Err(e) => Err(e.into()),
             },
-            m @ _ => {
-                return Err(format!("Message mismatch in wait next_without_order: {:?}", m).into())
-            }
+            m => Err(format!("Message mismatch in wait next_without_order: {:?}", m).into()),
         }
     }
 
-    fn next_heap_data(&self, index: u32, order_by: &OrderBy) -> Result<Option<HeapData>> {
-        if let Some(val) = self.next_index(index)? {
-            let column_val =
-                if let Some(SqlValue::Integer(n)) = val.get(order_by.column_index as usize) {
-                    *n
-                } else {
-                    return Err("Order by column type must be integer".into());
-                };
-            return Ok(Some(HeapData(index, column_val, val)));
+    fn next_heap_data(&self, index: u32, sort_keys: &Rc<Vec<SortKey>>) -> Result<Option<HeapData>> {
+        if let Some(data) = self.next_index(index)? {
+            let hidden_start = data
+                .len()
+                .saturating_sub(self.query.hidden_columns as usize);
+            let keys = sort_keys
+                .iter()
+                .map(|SortKey { order_by: o, .. }| {
+                    let index = match o.hidden {
+                        true => hidden_start + o.column_index as usize,
+                        false => o.column_index as usize,
+                    };
+                    data.get(index).cloned().ok_or_else(|| {
+                        format!("Order by column {} not found", o.column_index).into()
+                    })
+                })
+                .collect::<Result<Vec<SqlValue>>>()?;
+            return Ok(Some(HeapData {
+                index,
+                keys,
+                data,
+                sort_keys: sort_keys.clone(),
+            }));
         }
 
         Ok(None)
     }
 }
 
+/// Merges the result of `aggregate` on one shard into the result of the previous shards, NULL
+/// is the result of no rows.
+fn merge_aggregate(aggregate: &Aggregate, acc: SqlValue, value: SqlValue) -> Result<SqlValue> {
+    Ok(match (aggregate, acc, value) {
+        (_, acc, SqlValue::Null) => acc,
+        (_, SqlValue::Null, value) => value,
+        (Aggregate::Count(_), SqlValue::Integer(a), SqlValue::Integer(b))
+        | (Aggregate::Sum(_), SqlValue::Integer(a), SqlValue::Integer(b)) => {
+            SqlValue::Integer(a.checked_add(b).ok_or("integer overflow")?)
+        }
+        (Aggregate::Count(_), a, b) | (Aggregate::Sum(_), a, b) | (Aggregate::Total(_), a, b) => {
+            SqlValue::Real(as_real(&a)? + as_real(&b)?)
+        }
+        (Aggregate::Min(_), a, b) => match b.sqlite_cmp(&a) {
+            Ordering::Less => b,
+            _ => a,
+        },
+        (Aggregate::Max(_), a, b) => match b.sqlite_cmp(&a) {
+            Ordering::Greater => b,
+            _ => a,
+        },
+        (Aggregate::GroupConcat(_, separator), SqlValue::Text(mut a), SqlValue::Text(b)) => {
+            a.extend_from_slice(separator.as_bytes());
+            a.extend_from_slice(&b);
+            SqlValue::Text(a)
+        }
+        (a, acc, value) => {
+            return Err(format!("Can not merge {:?} and {:?} in {:?}", acc, value, a).into())
+        }
+    })
+}
+
+fn as_real(v: &SqlValue) -> Result<f64> {
+    match v {
+        SqlValue::Integer(n) => Ok(*n as f64),
+        SqlValue::Real(f) => Ok(*f),
+        v => Err(format!("Expect number in aggregate, received {:?}", v).into()),
+    }
+}
+
 impl Drop for Rows<'_> {
     fn drop(&mut self) {
         for i in &self.sharding_index_list {
@@ -615,10 +1500,7 @@ where
     type Item = Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.rows
-            .next()
-            .transpose()
-            .map(|row| Ok((self.map)(&row?)?))
+        self.rows.next().transpose().map(|row| (self.map)(&row?))
     }
 }
 
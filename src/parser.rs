@@ -1,10 +1,23 @@
 use crate::Result;
 use sqlparser::{
-    ast::{Expr, FunctionArg, Select, SelectItem, SetExpr},
-    dialect::Dialect,
+    ast::{
+        Assignment, Expr, FunctionArg, Ident, ObjectName, Select, SelectItem, SetExpr, TableAlias,
+        TableFactor, Value,
+    },
+    dialect::SQLiteDialect,
+    parser::IsOptional,
+    tokenizer::{Token, Tokenizer},
 };
+use std::{convert::TryFrom, fmt};
 
-const RUSQLITE_DIALECT: RusqliteDialect = RusqliteDialect;
+const SQLITE_DIALECT: SQLiteDialect = SQLiteDialect {};
+
+/// Placeholders are replaced by identifiers with this prefix before parsing, see
+/// `Parser::parse` and `Parser::restore_placeholders`.
+const PLACEHOLDER_PREFIX: &str = "__shardingite_param_";
+
+/// Largest placeholder number, SQLite's default `SQLITE_MAX_VARIABLE_NUMBER`.
+const MAX_PLACEHOLDER: usize = 32766;
 
 #[derive(Debug, Clone)]
 pub struct Limit {
@@ -14,65 +27,449 @@ pub struct Limit {
 
 #[derive(Debug, Clone)]
 pub struct OrderBy {
+    /// Index in the selected columns, or in the hidden columns if `hidden`
     pub column_index: u8,
     pub is_asc: bool,
+    /// Sort column appended to the select of every shard and stripped from rows
+    pub hidden: bool,
+    /// Collation of text values, `None` for BINARY
+    pub collation: Option<String>,
+    /// Explicit `NULLS FIRST` or `NULLS LAST`, otherwise NULL sorts as the smallest value
+    pub nulls_first: Option<bool>,
+    /// Table and column sorted by without an explicit collation, whose declared collation
+    /// applies
+    pub column: Option<(String, String)>,
 }
 
+/// Aggregate function of a selected column, with its argument, merged across shards.
 #[derive(Debug, Clone)]
 pub enum Aggregate {
+    /// `*` counts every row, `DISTINCT x` is only counted on each shard if `x` is the sharding
+    /// key
     Count(String),
+    /// Counted from the distinct values of every shard, the only column of its query
+    CountDistinct(String),
+    Sum(String),
+    Min(String),
+    Max(String),
+    /// Queried as `total(x), count(x)` on each shard
+    Avg(String),
+    Total(String),
+    /// Argument and separator
+    GroupConcat(String, String),
+}
+
+impl Aggregate {
+    /// Returns the aggregates queried on each shard to merge this one.
+    pub(crate) fn shard_aggregates(&self) -> Vec<Aggregate> {
+        match self {
+            Self::Avg(arg) => vec![Self::Total(arg.clone()), Self::Count(arg.clone())],
+            a => vec![a.clone()],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Query {
     pub limit: Option<Limit>,
-    pub order_by: Option<OrderBy>,
-    pub aggregate: Option<Aggregate>,
+    /// Sort keys in order of precedence, empty without ORDER BY
+    pub order_by: Vec<OrderBy>,
+    /// Number of hidden sort columns at the end of every row
+    pub hidden_columns: u8,
+    /// Aggregate of every selected column, empty if the query does not aggregate
+    pub aggregates: Vec<Aggregate>,
+}
+
+/// `ON CONFLICT` clause of an insert, which sqlparser 0.9 does not parse.
+#[derive(Debug, Clone)]
+pub struct Upsert {
+    pub target: Vec<Ident>,
+    pub target_selection: Option<Expr>,
+    /// Assignments and selection of `DO UPDATE`, `None` for `DO NOTHING`
+    pub update: Option<(Vec<Assignment>, Option<Expr>)>,
+}
+
+impl Upsert {
+    pub fn exprs_mut(&mut self) -> impl Iterator<Item = &mut Expr> {
+        let (assignments, selection) = match &mut self.update {
+            Some((assignments, selection)) => (Some(assignments), selection.as_mut()),
+            None => (None, None),
+        };
+
+        self.target_selection
+            .iter_mut()
+            .chain(assignments.into_iter().flatten().map(|a| &mut a.value))
+            .chain(selection)
+    }
+}
+
+impl fmt::Display for Upsert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ON CONFLICT")?;
+        if !self.target.is_empty() {
+            let target: Vec<String> = self.target.iter().map(|id| id.to_string()).collect();
+            write!(f, " ({})", target.join(", "))?;
+        }
+        if let Some(selection) = &self.target_selection {
+            write!(f, " WHERE {}", selection)?;
+        }
+        match &self.update {
+            Some((assignments, selection)) => {
+                let assignments: Vec<String> = assignments.iter().map(|a| a.to_string()).collect();
+                write!(f, " DO UPDATE SET {}", assignments.join(", "))?;
+                if let Some(selection) = selection {
+                    write!(f, " WHERE {}", selection)?;
+                }
+                Ok(())
+            }
+            None => write!(f, " DO NOTHING"),
+        }
+    }
+}
+
+pub struct ParsedSql {
+    pub ast: sqlparser::ast::Statement,
+    /// Placeholder name of every parameter number, `None` for the `?` and `?NNN` forms
+    pub param_names: Vec<Option<String>>,
+    /// Shards of a `/*+ shard(N, ...) */` hint
+    pub shards: Option<Vec<u32>>,
+    /// `ON CONFLICT` clause of an insert, split off before parsing
+    pub upsert: Option<Upsert>,
 }
 
 pub struct Parser {}
 
 impl Parser {
-    pub fn parse(sql: &str) -> Result<sqlparser::ast::Statement> {
-        let mut asts = sqlparser::parser::Parser::parse_sql(&RUSQLITE_DIALECT, sql)?;
-        if asts.len() == 0 {
+    /// Parses a single statement.
+    pub fn parse(sql: &str) -> Result<ParsedSql> {
+        let (sql, param_names, shards) = Self::replace_placeholders(sql)?;
+        let mut tokens = Tokenizer::new(&SQLITE_DIALECT, &sql)
+            .tokenize()
+            .map_err(sqlparser::parser::ParserError::from)?;
+
+        let upsert = match Self::find_upsert(&tokens) {
+            Some(pos) => {
+                let tail = tokens.split_off(pos);
+                Some(Self::parse_upsert(tail)?)
+            }
+            None => None,
+        };
+
+        let mut asts = Self::parse_statements(tokens)?;
+        if asts.is_empty() {
             Err("Empty sql".into())
         } else if asts.len() == 1 {
-            Ok(asts.pop().unwrap())
+            let ast = asts.pop().unwrap();
+            if upsert.is_some() && !matches!(ast, sqlparser::ast::Statement::Insert { .. }) {
+                return Err("ON CONFLICT is only supported in INSERT".into());
+            }
+
+            Ok(ParsedSql {
+                ast,
+                param_names,
+                shards,
+                upsert,
+            })
         } else {
             Err("Expect single sql, not list".into())
         }
     }
 
-    pub fn get_query_from_ast(ast: &sqlparser::ast::Statement) -> Result<Query> {
-        if let sqlparser::ast::Statement::Query(query) = ast {
-            let order_by = match query.order_by.get(0) {
-                Some(expr) => {
-                    Some(OrderBy {
-                        column_index: {
-                            // Get column name
-                            let column_name = match &expr.expr {
-                                sqlparser::ast::Expr::Identifier(ident) => ident.value.to_string(),
-                                _ => return Err("Expect ident in order expr".into()),
-                            };
-
-                            // Search column index
-                            let column_index = if let sqlparser::ast::SetExpr::Select(select) =
-                                &query.body
-                            {
-                                Self::find_column_index_in_select(select, &column_name)?
-                            } else {
-                                return Err("Currently only supports the combination of order by and select".into());
-                            };
-
-                            column_index
-                        },
-                        is_asc: expr.asc.unwrap_or(true),
-                    })
+    /// Same as `sqlparser::parser::Parser::parse_sql`, on tokens.
+    fn parse_statements(tokens: Vec<Token>) -> Result<Vec<sqlparser::ast::Statement>> {
+        let mut parser = sqlparser::parser::Parser::new(tokens, &SQLITE_DIALECT);
+        let mut asts = vec![];
+        loop {
+            while parser.consume_token(&Token::SemiColon) {}
+            match parser.peek_token() {
+                Token::EOF => break,
+                token if !asts.is_empty() => {
+                    return Err(format!("Expected end of statement, found: {}", token).into())
+                }
+                _ => asts.push(parser.parse_statement()?),
+            }
+        }
+
+        Ok(asts)
+    }
+
+    /// Returns the position of the `ON` of a top-level `ON CONFLICT (...)` or
+    /// `ON CONFLICT DO`.
+    fn find_upsert(tokens: &[Token]) -> Option<usize> {
+        let words: Vec<(usize, &Token)> = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| !matches!(t, Token::Whitespace(_)))
+            .collect();
+
+        let mut depth = 0;
+        for (i, (pos, token)) in words.iter().enumerate() {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                _ if depth == 0 && is_word(token, "ON") => {
+                    let next = words.get(i + 1).map(|(_, t)| *t);
+                    let after = words.get(i + 2).map(|(_, t)| *t);
+                    if next.is_some_and(|t| is_word(t, "CONFLICT"))
+                        && after.is_some_and(|t| *t == Token::LParen || is_word(t, "DO"))
+                    {
+                        return Some(*pos);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Parses `ON CONFLICT [(<columns>) [WHERE <expr>]] DO NOTHING` or
+    /// `... DO UPDATE SET <assignments> [WHERE <expr>]`.
+    fn parse_upsert(tokens: Vec<Token>) -> Result<Upsert> {
+        let mut parser = sqlparser::parser::Parser::new(tokens, &SQLITE_DIALECT);
+        let expect_word = |parser: &mut sqlparser::parser::Parser, word: &str| {
+            if is_word(&parser.peek_token(), word) {
+                parser.next_token();
+                Ok(())
+            } else {
+                Err(format!(
+                    "Expected {} in ON CONFLICT, found: {}",
+                    word,
+                    parser.peek_token()
+                ))
+            }
+        };
+        expect_word(&mut parser, "ON")?;
+        expect_word(&mut parser, "CONFLICT")?;
+
+        let target = parser.parse_parenthesized_column_list(IsOptional::Optional)?;
+        let mut target_selection = None;
+        if !target.is_empty() && is_word(&parser.peek_token(), "WHERE") {
+            parser.next_token();
+            target_selection = Some(parser.parse_expr()?);
+        }
+
+        expect_word(&mut parser, "DO")?;
+        let update = if is_word(&parser.peek_token(), "NOTHING") {
+            parser.next_token();
+            None
+        } else {
+            expect_word(&mut parser, "UPDATE")?;
+            expect_word(&mut parser, "SET")?;
+            let assignments =
+                parser.parse_comma_separated(sqlparser::parser::Parser::parse_assignment)?;
+            let selection = if is_word(&parser.peek_token(), "WHERE") {
+                parser.next_token();
+                Some(parser.parse_expr()?)
+            } else {
+                None
+            };
+            Some((assignments, selection))
+        };
+
+        while parser.consume_token(&Token::SemiColon) {}
+        if parser.peek_token() != Token::EOF {
+            return Err(format!(
+                "Expected end of ON CONFLICT, found: {}",
+                parser.peek_token()
+            )
+            .into());
+        }
+
+        Ok(Upsert {
+            target,
+            target_selection,
+            update,
+        })
+    }
+
+    /// Returns the parameter number of a placeholder expression.
+    pub fn get_placeholder(expr: &Expr) -> Option<usize> {
+        match expr {
+            Expr::Identifier(id) if id.quote_style.is_none() => id
+                .value
+                .strip_prefix(PLACEHOLDER_PREFIX)
+                .and_then(|n| n.parse().ok()),
+            _ => None,
+        }
+    }
+
+    /// Creates the placeholder identifier of parameter `number`.
+    pub fn placeholder(number: usize) -> Ident {
+        Ident::new(format!("{}{}", PLACEHOLDER_PREFIX, number))
+    }
+
+    /// Turns the placeholder identifiers of a formatted statement back into `?NNN`.
+    pub fn restore_placeholders(sql: &str) -> String {
+        let mut result = String::with_capacity(sql.len());
+        let mut rest = sql;
+        while let Some(pos) = rest.find(PLACEHOLDER_PREFIX) {
+            result.push_str(&rest[..pos]);
+            result.push('?');
+            rest = &rest[pos + PLACEHOLDER_PREFIX.len()..];
+        }
+        result.push_str(rest);
+
+        result
+    }
+
+    /// Numbers placeholders the way SQLite does: `?NNN` takes NNN, `?` takes the largest
+    /// number so far plus one, and `:AAA`, `@AAA`, `$AAA` reuse the number of the same name.
+    /// Shard hints are read from block comments on the way.
+    #[allow(clippy::type_complexity)]
+    fn replace_placeholders(sql: &str) -> Result<(String, Vec<Option<String>>, Option<Vec<u32>>)> {
+        let mut names: Vec<Option<String>> = vec![];
+        let mut hint: Option<Vec<u32>> = None;
+        let mut result = String::with_capacity(sql.len());
+        let mut chars = sql.chars().peekable();
+        let mut prev: Option<char> = None;
+
+        while let Some(ch) = chars.next() {
+            let number = match ch {
+                // Quoted strings and identifiers
+                '\'' | '"' | '`' | '[' => {
+                    let end = if ch == '[' { ']' } else { ch };
+                    result.push(ch);
+                    for ch in chars.by_ref() {
+                        result.push(ch);
+                        if ch == end {
+                            break;
+                        }
+                    }
+                    prev = Some(end);
+                    continue;
+                }
+                // Comments
+                '-' if chars.peek() == Some(&'-') => {
+                    result.push(ch);
+                    for ch in chars.by_ref() {
+                        result.push(ch);
+                        if ch == '\n' {
+                            break;
+                        }
+                    }
+                    prev = Some('\n');
+                    continue;
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    result.push(ch);
+                    result.push(chars.next().unwrap());
+                    let mut comment = String::new();
+                    let mut last = ' ';
+                    for ch in chars.by_ref() {
+                        result.push(ch);
+                        if last == '*' && ch == '/' {
+                            comment.pop();
+                            break;
+                        }
+                        comment.push(ch);
+                        last = ch;
+                    }
+                    if let Some(shards) = Self::parse_shard_hint(&comment)? {
+                        if hint.is_some() {
+                            return Err("Duplicate shard hint".into());
+                        }
+                        hint = Some(shards);
+                    }
+                    prev = Some('/');
+                    continue;
+                }
+                '?' => {
+                    let mut digits = String::new();
+                    while let Some(ch) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                        digits.push(*ch);
+                        chars.next();
+                    }
+
+                    if digits.is_empty() {
+                        names.push(None);
+                        names.len()
+                    } else {
+                        let number: usize = digits.parse()?;
+                        if number == 0 {
+                            return Err("Placeholder number must be greater than 0".into());
+                        }
+                        if number > MAX_PLACEHOLDER {
+                            return Err(format!(
+                                "Placeholder number {} exceeds the limit of {}",
+                                number, MAX_PLACEHOLDER
+                            )
+                            .into());
+                        }
+                        if names.len() < number {
+                            names.resize(number, None);
+                        }
+                        number
+                    }
+                }
+                ':' | '@' | '$'
+                    if !prev.is_some_and(is_identifier_char)
+                        && chars.peek().is_some_and(|c| is_identifier_char(*c)) =>
+                {
+                    let mut name = ch.to_string();
+                    while let Some(ch) = chars.peek().filter(|c| is_identifier_char(**c)) {
+                        name.push(*ch);
+                        chars.next();
+                    }
+
+                    match names.iter().position(|n| n.as_ref() == Some(&name)) {
+                        Some(index) => index + 1,
+                        None => {
+                            names.push(Some(name));
+                            names.len()
+                        }
+                    }
+                }
+                _ => {
+                    result.push(ch);
+                    prev = Some(ch);
+                    continue;
                 }
-                None => None,
             };
 
+            result.push_str(&Self::placeholder(number).value);
+            prev = Some('_');
+        }
+
+        Ok((result, names, hint))
+    }
+
+    /// Parses the body of a `/*+ shard(N, ...) */` comment, other comments are ignored.
+    fn parse_shard_hint(comment: &str) -> Result<Option<Vec<u32>>> {
+        let body = match comment.strip_prefix('+') {
+            Some(body) => body.trim(),
+            None => return Ok(None),
+        };
+        if !body.to_lowercase().starts_with("shard") {
+            return Ok(None);
+        }
+
+        let list = body["shard".len()..]
+            .trim()
+            .strip_prefix('(')
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(|| format!("Invalid shard hint '{}'", body))?;
+        let shards = list
+            .split(',')
+            .map(|s| {
+                s.trim()
+                    .parse::<u32>()
+                    .map_err(|e| format!("Invalid shard hint '{}': {}", body, e).into())
+            })
+            .collect::<Result<Vec<u32>>>()?;
+
+        Ok(Some(shards))
+    }
+
+    /// Returns how to merge the rows of a query, `distinct_on_key` if its `count(DISTINCT x)`
+    /// count the sharding key, see `Router::is_distinct_on_key`.
+    pub fn get_query_from_ast(
+        ast: &sqlparser::ast::Statement,
+        distinct_on_key: bool,
+    ) -> Result<Query> {
+        if let sqlparser::ast::Statement::Query(query) = ast {
+            let (order_by, hidden_columns) = Self::get_order_by(query)?;
+
             let limit_number: Option<u32> = match &query.limit {
                 Some(e) => match e {
                     sqlparser::ast::Expr::Value(v) => match v {
@@ -95,104 +492,391 @@ impl Parser {
                 None => None,
             };
 
-            let limit = match limit_number {
-                Some(l) => Some(Limit {
-                    limit: l,
-                    offset: offset_number.unwrap_or(0),
-                }),
-                None => None,
-            };
+            let limit = limit_number.map(|l| Limit {
+                limit: l,
+                offset: offset_number.unwrap_or(0),
+            });
 
-            let aggregate = Self::get_aggregate(query)?;
+            let aggregates = Self::get_aggregates(query, distinct_on_key)?;
 
             return Ok(Query {
                 limit,
                 order_by,
-                aggregate,
+                hidden_columns: hidden_columns.len() as u8,
+                aggregates,
             });
         }
 
         Err("Not a query".into())
     }
 
-    fn get_aggregate(query: &sqlparser::ast::Query) -> Result<Option<Aggregate>> {
-        match &query.body {
-            SetExpr::Select(sel) => match sel.as_ref() {
-                Select { projection, .. } => {
-                    for prj in projection {
-                        match prj {
-                            SelectItem::UnnamedExpr(expr) => {
-                                if let Expr::Function(f) = expr {
-                                    let name = f.name.0[0].value.clone();
-                                    match name.as_str().to_lowercase().as_str() {
-                                        "count" => {
-                                            if projection.len() > 1 {
-                                                return Err("Currently only supports count function alone in query function".into());
-                                            }
-
-                                            if let Some(FunctionArg::Unnamed(Expr::Wildcard)) =
-                                                &f.args.get(0)
-                                            {
-                                                return Ok(Some(Aggregate::Count("*".to_string())));
-                                            }
-
-                                            return Err("Currently only supports * with count function in query function".into());
-                                        }
-                                        "json_extract" => {}
-                                        _ => {
-                                            return {
-                                                Err("Currently only supports ['count', 'json_extract'] filed in query function"
-                                                    .into())
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return Err("Currently only supports unname expr in query".into());
-                            }
-                        }
+    /// Returns the aggregate of every selected column, the projection must be aggregate
+    /// functions only if it has one.
+    fn get_aggregates(
+        query: &sqlparser::ast::Query,
+        distinct_on_key: bool,
+    ) -> Result<Vec<Aggregate>> {
+        let select = match &query.body {
+            SetExpr::Select(sel) => sel.as_ref(),
+            _ => return Err("Currently only supports select query".into()),
+        };
+
+        let mut aggregates = vec![];
+        for prj in &select.projection {
+            let expr = match prj {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr,
+                _ => continue,
+            };
+            let f = match expr {
+                Expr::Function(f) if Self::is_aggregate_function(f) => f,
+                e if Self::has_aggregate(e) => {
+                    return Err(format!(
+                        "Currently only supports aggregate function alone in column, received {}",
+                        e
+                    )
+                    .into())
+                }
+                _ => continue,
+            };
+
+            let name = f.name.to_string().to_lowercase();
+            let (arg, rest) = match f.args.as_slice() {
+                [FunctionArg::Unnamed(arg), rest @ ..] => (arg.to_string(), rest),
+                _ => return Err(format!("Invalid arguments of aggregate function {}", f).into()),
+            };
+            if f.distinct {
+                aggregates.push(match (name.as_str(), rest) {
+                    ("count", []) if distinct_on_key => {
+                        Aggregate::Count(format!("DISTINCT {}", arg))
                     }
+                    ("count", []) => Aggregate::CountDistinct(arg),
+                    _ => return Err(format!("Currently not supports DISTINCT in {}", f).into()),
+                });
+                continue;
+            }
+            aggregates.push(match (name.as_str(), rest) {
+                ("count", []) => Aggregate::Count(arg),
+                ("sum", []) => Aggregate::Sum(arg),
+                ("min", []) => Aggregate::Min(arg),
+                ("max", []) => Aggregate::Max(arg),
+                ("avg", []) => Aggregate::Avg(arg),
+                ("total", []) => Aggregate::Total(arg),
+                ("group_concat", []) => Aggregate::GroupConcat(arg, ",".to_string()),
+                (
+                    "group_concat",
+                    [FunctionArg::Unnamed(Expr::Value(Value::SingleQuotedString(separator)))],
+                ) => Aggregate::GroupConcat(arg, separator.to_string()),
+                _ => return Err(format!("Currently not supports aggregate function {}", f).into()),
+            });
+        }
+
+        if aggregates.is_empty() {
+            return Ok(aggregates);
+        }
+        if aggregates.len() > 1
+            && aggregates
+                .iter()
+                .any(|a| matches!(a, Aggregate::CountDistinct(_)))
+        {
+            return Err(
+                "Currently only supports count(DISTINCT) of a column other than the sharding key alone"
+                    .into(),
+            );
+        }
+        if aggregates.len() != select.projection.len() {
+            return Err("Currently only supports aggregate functions without other columns".into());
+        }
+        if !select.group_by.is_empty() || select.having.is_some() {
+            return Err(
+                "Currently not supports GROUP BY or HAVING with aggregate functions".into(),
+            );
+        }
+
+        Ok(aggregates)
+    }
+
+    /// Returns true if `f` is an aggregate function, `min` and `max` of several arguments are
+    /// scalar functions and calls with `OVER` are window functions.
+    fn is_aggregate_function(f: &sqlparser::ast::Function) -> bool {
+        if f.over.is_some() {
+            return false;
+        }
+        match f.name.to_string().to_lowercase().as_str() {
+            "count" | "sum" | "avg" | "total" | "group_concat" => true,
+            "min" | "max" => f.args.len() == 1,
+            _ => false,
+        }
+    }
+
+    /// Returns true if an aggregate function is used anywhere in `expr`.
+    pub fn has_aggregate(expr: &Expr) -> bool {
+        Self::has_function(expr, &Self::is_aggregate_function)
+    }
+
+    /// Returns true if a window function, a call with `OVER`, is used anywhere in `expr`.
+    pub fn has_window_function(expr: &Expr) -> bool {
+        Self::has_function(expr, &|f| f.over.is_some())
+    }
+
+    /// Returns true if a function call matching `is_match` is used anywhere in `expr`.
+    fn has_function(expr: &Expr, is_match: &dyn Fn(&sqlparser::ast::Function) -> bool) -> bool {
+        let has_function = |e: &Expr| Self::has_function(e, is_match);
+        match expr {
+            Expr::Function(f) => {
+                is_match(f)
+                    || f.args.iter().any(|arg| match arg {
+                        FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => {
+                            has_function(arg)
+                        }
+                    })
+            }
+            Expr::BinaryOp { left, right, .. } => has_function(left) || has_function(right),
+            Expr::UnaryOp { expr, .. }
+            | Expr::Nested(expr)
+            | Expr::Cast { expr, .. }
+            | Expr::Collate { expr, .. } => has_function(expr),
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => operand
+                .iter()
+                .chain(else_result.iter())
+                .map(|e| e.as_ref())
+                .chain(conditions.iter())
+                .chain(results.iter())
+                .any(has_function),
+            _ => false,
+        }
+    }
+
+    /// Resolves the ORDER BY keys of a query against its projection by ordinal, alias or equal
+    /// expression. Keys which are not selected are returned as hidden columns, which must be
+    /// appended to the projection.
+    pub fn get_order_by(query: &sqlparser::ast::Query) -> Result<(Vec<OrderBy>, Vec<Expr>)> {
+        let mut order_by = Vec::with_capacity(query.order_by.len());
+        let mut hidden: Vec<Expr> = vec![];
+        if query.order_by.is_empty() {
+            return Ok((order_by, hidden));
+        }
+
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => {
+                return Err("Currently only supports the combination of order by and select".into())
+            }
+        };
+
+        for expr in &query.order_by {
+            let (term, collation) = match &expr.expr {
+                Expr::Collate { expr, collation } => (expr.as_ref(), Some(collation)),
+                e => (e, None),
+            };
+            let (index, sort_expr) = Self::find_column_index_in_select(select, term)?;
+
+            // The collation of the term, or else of the selected expression
+            let collation = match (collation, &sort_expr) {
+                (Some(collation), _) | (None, Expr::Collate { collation, .. }) => {
+                    collation.0.last().map(|id| id.value.to_string())
+                }
+                _ => None,
+            };
+            let column = match collation {
+                Some(_) => None,
+                None => Self::get_table_column(select, &sort_expr),
+            };
 
-                    Ok(None)
+            let (index, is_hidden) = match (index, sort_expr) {
+                (Some(index), _) => (index, false),
+                (None, sort_expr) => {
+                    if select.distinct {
+                        return Err(
+                            "ORDER BY of a DISTINCT select must use selected columns".into()
+                        );
+                    }
+                    let index = match hidden.iter().position(|e| is_same_expr(e, &sort_expr)) {
+                        Some(index) => index,
+                        None => {
+                            hidden.push(sort_expr);
+                            hidden.len() - 1
+                        }
+                    };
+                    (index, true)
                 }
-            },
-            _ => Err("Currently only supports select query".into()),
+            };
+
+            order_by.push(OrderBy {
+                column_index: u8::try_from(index)
+                    .map_err(|_| format!("Too many columns to order by index {}", index))?,
+                is_asc: expr.asc.unwrap_or(true),
+                hidden: is_hidden,
+                collation,
+                nulls_first: expr.nulls_first,
+                column,
+            });
         }
+
+        Ok((order_by, hidden))
     }
 
-    fn find_column_index_in_select(select: &Select, column: &String) -> Result<u8> {
-        for (index, item) in select.projection.iter().enumerate() {
-            if let SelectItem::UnnamedExpr(e) = item {
-                if let Expr::Identifier(id) = e {
-                    if &id.value == column {
-                        return Ok(index as u8);
+    /// Returns the index of the selected column `expr` refers to, or `None`, and the expression
+    /// to sort by. Columns after a wildcard have no known index.
+    fn find_column_index_in_select(select: &Select, expr: &Expr) -> Result<(Option<usize>, Expr)> {
+        let known = select
+            .projection
+            .iter()
+            .position(|item| {
+                matches!(
+                    item,
+                    SelectItem::Wildcard | SelectItem::QualifiedWildcard(_)
+                )
+            })
+            .unwrap_or(select.projection.len());
+
+        // Ordinal
+        if let Expr::Value(sqlparser::ast::Value::Number(n, _)) = expr {
+            let n: usize = n
+                .parse()
+                .map_err(|_| format!("Invalid ORDER BY term {}", n))?;
+            return match n {
+                n if n >= 1 && n <= known => match &select.projection[n - 1] {
+                    SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => {
+                        Ok((Some(n - 1), e.clone()))
                     }
-                } else {
-                    return Err("Currently only supports the unnamed id expr in select".into());
+                    _ => Ok((Some(n - 1), expr.clone())),
+                },
+                n if n >= 1 && known < select.projection.len() => {
+                    Err(format!("ORDER BY {} after * in select is not supported", n).into())
                 }
-            } else {
-                return Err("Currently only supports the unnamed expr in select".into());
+                n => Err(format!("ORDER BY term {} out of range", n).into()),
+            };
+        }
+
+        // Alias
+        if let Expr::Identifier(id) = expr {
+            for (index, item) in select.projection.iter().enumerate() {
+                if let SelectItem::ExprWithAlias { expr, alias } = item {
+                    if alias.value.eq_ignore_ascii_case(&id.value) {
+                        return Ok(((index < known).then_some(index), expr.clone()));
+                    }
+                }
+            }
+        }
+
+        // Selected expression
+        for (index, item) in select.projection[..known].iter().enumerate() {
+            match item {
+                SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. }
+                    if is_same_expr(e, expr) =>
+                {
+                    return Ok((Some(index), e.clone()))
+                }
+                _ => {}
             }
         }
 
-        Err(format!("Column '{}' not found in select", column).into())
+        Ok((None, expr.clone()))
     }
-}
 
-#[derive(Debug, Default)]
-struct RusqliteDialect;
+    /// Returns the table and column `expr` refers to, if it is a column of a table in the FROM
+    /// clause. An unqualified column is only resolved with a single table.
+    fn get_table_column(select: &Select, expr: &Expr) -> Option<(String, String)> {
+        let tables: Vec<(&ObjectName, &Option<TableAlias>)> = select
+            .from
+            .iter()
+            .flat_map(|t| std::iter::once(&t.relation).chain(t.joins.iter().map(|j| &j.relation)))
+            .filter_map(|r| match r {
+                TableFactor::Table { name, alias, .. } => Some((name, alias)),
+                _ => None,
+            })
+            .collect();
+
+        let (qualifier, column) = match expr {
+            Expr::Identifier(column) => (None, column),
+            Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                (Some(&idents[idents.len() - 2]), &idents[idents.len() - 1])
+            }
+            _ => return None,
+        };
+
+        let table = match qualifier {
+            None if select.from.len() == 1 && select.from[0].joins.is_empty() => tables.first(),
+            None => None,
+            Some(qualifier) => tables.iter().find(|(name, alias)| {
+                alias
+                    .as_ref()
+                    .map(|alias| &alias.name)
+                    .or_else(|| name.0.last())
+                    .is_some_and(|r| r.value.eq_ignore_ascii_case(&qualifier.value))
+            }),
+        }?;
 
-impl Dialect for RusqliteDialect {
-    fn is_identifier_start(&self, ch: char) -> bool {
-        ('a'..='z').contains(&ch) || ('A'..='Z').contains(&ch) || ch == '_' || ch == '?'
+        Some((table.0 .0.last()?.value.clone(), column.value.clone()))
     }
 
-    fn is_identifier_part(&self, ch: char) -> bool {
-        ('a'..='z').contains(&ch)
-            || ('A'..='Z').contains(&ch)
-            || ('0'..='9').contains(&ch)
-            || ch == '_'
-            || ch == '?'
+    /// Returns the collation declared on `column` in the `CREATE TABLE` statement `sql` of
+    /// `sqlite_master`.
+    pub fn get_declared_collation(sql: &str, column: &str) -> Result<Option<String>> {
+        let tokens = Tokenizer::new(&SQLiteDialect {}, sql)
+            .tokenize()
+            .map_err(|e| format!("Failed to tokenize table schema: {:?}", e))?;
+        let tokens: Vec<&Token> = tokens
+            .iter()
+            .filter(|t| !matches!(t, Token::Whitespace(_)))
+            .collect();
+
+        // Split the column definitions on the commas between them
+        let mut depth = 0;
+        let mut definitions: Vec<Vec<&Token>> = vec![vec![]];
+        for token in tokens {
+            match token {
+                Token::LParen => {
+                    depth += 1;
+                    if depth == 1 {
+                        continue;
+                    }
+                }
+                Token::RParen => depth -= 1,
+                Token::Comma if depth == 1 => {
+                    definitions.push(vec![]);
+                    continue;
+                }
+                _ => {}
+            }
+            if depth >= 1 {
+                if let Some(definition) = definitions.last_mut() {
+                    definition.push(token);
+                }
+            }
+        }
+
+        let definition = definitions.iter().find(
+            |d| matches!(d.first(), Some(Token::Word(w)) if w.value.eq_ignore_ascii_case(column)),
+        );
+        let collation = definition.and_then(|d| {
+            d.windows(2).find_map(|pair| match pair {
+                [collate, Token::Word(w)] if is_word(collate, "COLLATE") => Some(w.value.clone()),
+                _ => None,
+            })
+        });
+
+        Ok(collation)
     }
 }
+
+/// Identifiers are compared ignoring ASCII case like SQLite does.
+fn is_same_expr(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Identifier(a), Expr::Identifier(b)) => a.value.eq_ignore_ascii_case(&b.value),
+        (a, b) => a == b,
+    }
+}
+
+fn is_word(token: &Token, word: &str) -> bool {
+    matches!(token, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word))
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_' || ch == '$'
+}
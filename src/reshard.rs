@@ -0,0 +1,483 @@
+use crate::{BucketMap, Result, ShardingIteConfig, ShardingRule, ShardingStrategy, SqlParam};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+const BATCH_SIZE: u32 = 1000;
+
+/// Table of a shard file listing the rows of the batch last copied into it as
+/// `(source, tbl, id)`, until they are deleted from their source file.
+const JOURNAL: &str = "_shardingite_reshard";
+
+#[derive(Debug, Clone, Default)]
+pub struct ReshardReport {
+    /// Rows moved to another shard file
+    pub moved_rows: u64,
+    /// Row count of every sharding table, checked to be the same before and after
+    pub table_rows: Vec<(String, u64)>,
+}
+
+/// Moves the rows of sharding tables from one shard set to another.
+///
+/// Every shard file is opened once in exclusive locking mode and stays locked for the whole
+/// run, so nothing else reads or writes it meanwhile. A batch is copied into its target file
+/// together with a journal of the copied rows, then deleted from the source file. Running an
+/// interrupted reshard again first deletes the journaled rows left in their source, then picks
+/// up the rest.
+pub struct Resharder;
+
+impl Resharder {
+    pub fn run(from: &ShardingIteConfig, to: &mut ShardingIteConfig) -> Result<ReshardReport> {
+        let from_paths: Vec<String> = (0..from.sharding_count)
+            .map(|i| from.sharding_path.as_ref()(i))
+            .collect();
+        let to_paths: Vec<String> = (0..to.sharding_count)
+            .map(|i| to.sharding_path.as_ref()(i))
+            .collect();
+        let source = from_paths.first().ok_or("Sharding count of source is 0")?;
+        if to_paths.is_empty() {
+            return Err("Sharding count of target is 0".into());
+        }
+        for rule in &to.sharding_rules {
+            rule.strategy
+                .validate()
+                .map_err(|e| format!("Invalid strategy of table '{}': {}", rule.table, e))?;
+        }
+
+        // New bucket maps are written aside and only replace the old ones once rows are moved
+        for rule in &mut to.sharding_rules {
+            if let ShardingStrategy::Bucket(map) = &mut rule.strategy {
+                Self::prepare_bucket_map(from, &rule.table, map, &to_paths[0], to.sharding_count)?;
+            }
+        }
+
+        let mut paths = from_paths.clone();
+        paths.extend(to_paths.iter().filter(|p| !from_paths.contains(p)).cloned());
+
+        // Lock every file before anything is read
+        let mut conns: BTreeMap<&str, Connection> = BTreeMap::new();
+        for path in &paths {
+            conns.insert(path, Self::lock(path)?);
+        }
+
+        // Schema, and tables copied to every shard for new shard files
+        for path in &to_paths {
+            if !from_paths.contains(path) {
+                Self::copy_schema(
+                    &conns[source.as_str()],
+                    &conns[path.as_str()],
+                    &to.sharding_rules,
+                )?;
+            }
+        }
+
+        Self::recover(&conns)?;
+
+        let mut report = ReshardReport::default();
+        for rule in &to.sharding_rules {
+            let before = Self::count_rows(&conns, &paths, &rule.table)?;
+            for path in &paths {
+                report.moved_rows +=
+                    Self::move_rows(&conns, path, rule, &to_paths, to.sharding_count)?;
+            }
+            let after = Self::count_rows(&conns, &paths, &rule.table)?;
+            if before != after {
+                return Err(format!(
+                    "Row count of table '{}' mismatch, {} before, {} after",
+                    rule.table, before, after
+                )
+                .into());
+            }
+            let left = Self::count_rows(
+                &conns,
+                &paths
+                    .iter()
+                    .filter(|p| !to_paths.contains(p))
+                    .cloned()
+                    .collect::<Vec<String>>(),
+                &rule.table,
+            )?;
+            if left != 0 {
+                return Err(format!(
+                    "{} rows of table '{}' left in removed shards",
+                    left, rule.table
+                )
+                .into());
+            }
+            report.table_rows.push((rule.table.to_string(), after));
+        }
+
+        for conn in conns.values() {
+            conn.execute(&format!("DROP TABLE IF EXISTS {}", JOURNAL), [])?;
+        }
+
+        for rule in &to.sharding_rules {
+            if let ShardingStrategy::Bucket(_) = &rule.strategy {
+                let path = BucketMap::path_of(&to_paths[0], &rule.table);
+                std::fs::rename(path.with_extension("buckets.new"), &path)?;
+            }
+        }
+
+        log::info!("Reshard done: {:?}", report);
+        Ok(report)
+    }
+
+    fn prepare_bucket_map(
+        from: &ShardingIteConfig,
+        table: &str,
+        map: &mut BucketMap,
+        sharding_path: &str,
+        sharding_count: u32,
+    ) -> Result<()> {
+        let path = BucketMap::path_of(sharding_path, table).with_extension("buckets.new");
+        if path.exists() {
+            // Resume with the map of the interrupted run
+            let new_map = BucketMap::load(&path)?;
+            if new_map.buckets() != map.buckets() {
+                return Err(format!(
+                    "Bucket map {:?} has {} buckets, expect {}",
+                    path,
+                    new_map.buckets(),
+                    map.buckets()
+                )
+                .into());
+            }
+            *map = new_map;
+            return Ok(());
+        }
+
+        let old_map = from.sharding_rules.iter().find_map(|r| match &r.strategy {
+            ShardingStrategy::Bucket(m) if r.table == table && m.buckets() == map.buckets() => {
+                Some(m)
+            }
+            _ => None,
+        });
+        match old_map {
+            Some(old_map) => {
+                *map = old_map.clone();
+                let moves = map.rebalance(sharding_count)?;
+                log::debug!("Rebalance buckets of table '{}': {:?}", table, moves);
+            }
+            None => map.assign(sharding_count)?,
+        }
+
+        map.save(&path)
+    }
+
+    fn copy_schema(source: &Connection, target: &Connection, rules: &[ShardingRule]) -> Result<()> {
+        let objects: Vec<(String, String, String)> = source
+            .prepare(
+                "SELECT type, name, sql FROM sqlite_master
+                WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' AND name != ?1
+                ORDER BY type = 'table' DESC",
+            )?
+            .query_map([JOURNAL], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let tx = target.unchecked_transaction()?;
+        for (kind, name, sql) in objects {
+            let exists: bool = tx.query_row(
+                "SELECT count(*) > 0 FROM sqlite_master WHERE name = ?1",
+                [&name],
+                |row| row.get(0),
+            )?;
+            if exists {
+                continue;
+            }
+
+            log::debug!("Create {} '{}'", kind, name);
+            tx.execute(&sql, [])?;
+            if kind == "table" && !rules.iter().any(|r| r.table.eq_ignore_ascii_case(&name)) {
+                let rows =
+                    Self::query_rows(source, &format!("SELECT * FROM {}", quote(&name)), [])?;
+                Self::insert_rows(&tx, &name, rows)?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Deletes from their source file the rows journaled by a batch which was interrupted
+    /// before deleting them, then clears the journals.
+    fn recover(conns: &BTreeMap<&str, Connection>) -> Result<()> {
+        for (path, conn) in conns {
+            if !Self::has_table(conn, JOURNAL)? {
+                continue;
+            }
+
+            let mut copied: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+            let entries = conn
+                .prepare(&format!("SELECT source, tbl, id FROM {}", JOURNAL))?
+                .query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?
+                .collect::<rusqlite::Result<Vec<((String, String), String)>>>()?;
+            for (source, id) in entries {
+                copied.entry(source).or_default().push(id);
+            }
+
+            for ((source, table), ids) in copied {
+                let source_conn = conns.get(source.as_str()).ok_or_else(|| {
+                    format!("Journal of {} refers to unknown shard {}", path, source)
+                })?;
+                let id = Self::get_row_id(&Self::get_row_id_columns(source_conn, &table)?);
+                let deleted = source_conn.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE {} IN ({})",
+                        quote(&table),
+                        id,
+                        vec!["?"; ids.len()].join(", ")
+                    ),
+                    params_from_iter(&ids),
+                )?;
+                log::debug!(
+                    "Recover {} copied rows of table '{}' from {}",
+                    deleted,
+                    table,
+                    source
+                );
+            }
+            conn.execute(&format!("DELETE FROM {}", JOURNAL), [])?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the rows of `path` that belong to another shard, returns the moved row count.
+    fn move_rows(
+        conns: &BTreeMap<&str, Connection>,
+        path: &str,
+        rule: &ShardingRule,
+        to_paths: &[String],
+        sharding_count: u32,
+    ) -> Result<u64> {
+        let conn = &conns[path];
+        if !Self::has_table(conn, &rule.table)? {
+            return Ok(0);
+        }
+
+        let table = quote(&rule.table);
+        let id_columns = Self::get_row_id_columns(conn, &rule.table)?;
+        let id = Self::get_row_id(&id_columns);
+        let ids = id_columns.join(", ");
+        let id_count = id_columns.len();
+        let columns = rule
+            .columns
+            .iter()
+            .map(|c| quote(c))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let mut moved = 0;
+        let mut last_id: Option<Vec<Value>> = None;
+        loop {
+            // Page through the row ids, compared as row values to support composite keys
+            let after = match &last_id {
+                Some(_) => format!(" WHERE ({}) > ({})", ids, vec!["?"; id_count].join(", ")),
+                None => String::new(),
+            };
+            let rows: Vec<(Vec<Value>, Vec<Value>)> = conn
+                .prepare(&format!(
+                    "SELECT {0}, {1} FROM {2}{3} ORDER BY {0} LIMIT {4}",
+                    ids, columns, table, after, BATCH_SIZE
+                ))?
+                .query_map(params_from_iter(last_id.iter().flatten()), |row| {
+                    let mut id = Vec::with_capacity(id_count);
+                    for i in 0..id_count {
+                        id.push(row.get(i)?);
+                    }
+                    let mut key = Vec::with_capacity(rule.columns.len());
+                    for i in 0..rule.columns.len() {
+                        key.push(row.get(id_count + i)?);
+                    }
+                    Ok((id, key))
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            let last = match rows.last() {
+                Some((id, _)) => id.clone(),
+                None => break,
+            };
+
+            let mut targets: BTreeMap<&str, Vec<Vec<Value>>> = BTreeMap::new();
+            for (id, values) in rows {
+                let mut key = Vec::with_capacity(values.len());
+                for value in values {
+                    key.push(match value {
+                        Value::Integer(n) => SqlParam::I64(n),
+                        Value::Real(f) => SqlParam::F64(f),
+                        Value::Text(s) => SqlParam::String(s),
+                        Value::Blob(b) => SqlParam::Blob(b),
+                        Value::Null => SqlParam::Null,
+                    });
+                }
+                let target = &to_paths[rule.strategy.index(&key, sharding_count)? as usize];
+                if target != path {
+                    targets.entry(target).or_default().push(id);
+                }
+            }
+
+            for (target, moving) in targets {
+                let selection = format!(
+                    "({}) IN (VALUES {})",
+                    ids,
+                    vec![format!("({})", vec!["?"; id_count].join(", ")); moving.len()].join(", ")
+                );
+                let params = || params_from_iter(moving.iter().flatten());
+
+                // Copy with a journal of the copied rows, then delete them from the source
+                let rows = Self::query_rows(
+                    conn,
+                    &format!("SELECT {}, * FROM {} WHERE {}", id, table, selection),
+                    params(),
+                )?;
+                let tx = conns[target].unchecked_transaction()?;
+                tx.execute(
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {} (source TEXT NOT NULL, tbl TEXT NOT NULL, id TEXT NOT NULL)",
+                        JOURNAL
+                    ),
+                    [],
+                )?;
+                let mut copied = Vec::with_capacity(rows.len());
+                {
+                    let mut journal = tx.prepare(&format!(
+                        "INSERT INTO {} (source, tbl, id) VALUES (?1, ?2, ?3)",
+                        JOURNAL
+                    ))?;
+                    for mut row in rows {
+                        journal.execute(rusqlite::params![path, rule.table, row.remove(0)])?;
+                        copied.push(row);
+                    }
+                }
+                Self::insert_rows(&tx, &rule.table, copied)?;
+                tx.commit()?;
+
+                moved += conn.execute(
+                    &format!("DELETE FROM {} WHERE {}", table, selection),
+                    params(),
+                )? as u64;
+                conns[target].execute(&format!("DELETE FROM {}", JOURNAL), [])?;
+            }
+
+            last_id = Some(last);
+        }
+
+        log::debug!(
+            "Move {} rows of table '{}' from {}",
+            moved,
+            rule.table,
+            path
+        );
+        Ok(moved)
+    }
+
+    fn count_rows(
+        conns: &BTreeMap<&str, Connection>,
+        paths: &[String],
+        table: &str,
+    ) -> Result<u64> {
+        let mut count = 0;
+        for path in paths {
+            let conn = &conns[path.as_str()];
+            if Self::has_table(conn, table)? {
+                let n: i64 = conn.query_row(
+                    &format!("SELECT count(*) FROM {}", quote(table)),
+                    [],
+                    |row| row.get(0),
+                )?;
+                count += n as u64;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Returns the columns identifying a row, `rowid` or else the primary key of a
+    /// `WITHOUT ROWID` table.
+    fn get_row_id_columns(conn: &Connection, table: &str) -> Result<Vec<String>> {
+        let quoted = quote(table);
+        if conn
+            .prepare(&format!("SELECT rowid FROM {} LIMIT 0", quoted))
+            .is_ok()
+        {
+            return Ok(vec!["rowid".to_string()]);
+        }
+
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info(?1) WHERE pk > 0 ORDER BY pk")?
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .map(|name| name.map(|n| quote(&n)))
+            .collect::<rusqlite::Result<_>>()?;
+        if columns.is_empty() {
+            return Err(format!("Table {} has neither rowid nor primary key", quoted).into());
+        }
+
+        Ok(columns)
+    }
+
+    fn has_table(conn: &Connection, table: &str) -> Result<bool> {
+        Ok(conn.query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE",
+            [table],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Returns the text identifying a row in the journal, its id columns quoted as SQL literals.
+    fn get_row_id(id_columns: &[String]) -> String {
+        let quoted = id_columns
+            .iter()
+            .map(|c| format!("quote({})", c))
+            .collect::<Vec<String>>();
+
+        // quote() returns numbers as is, compare them as text
+        format!("CAST({} AS TEXT)", quoted.join(" || ',' || "))
+    }
+
+    fn query_rows<P: rusqlite::Params>(
+        conn: &Connection,
+        sql: &str,
+        params: P,
+    ) -> Result<Vec<Vec<Value>>> {
+        let mut stmt = conn.prepare(sql)?;
+        let width = stmt.column_count();
+        let rows = stmt
+            .query_map(params, |row| (0..width).map(|i| row.get(i)).collect())?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(rows)
+    }
+
+    fn insert_rows(conn: &Connection, table: &str, rows: Vec<Vec<Value>>) -> Result<()> {
+        let width = match rows.first() {
+            Some(row) => row.len(),
+            None => return Ok(()),
+        };
+        let mut stmt = conn.prepare(&format!(
+            "INSERT INTO {} VALUES ({})",
+            quote(table),
+            vec!["?"; width].join(", ")
+        ))?;
+        for row in rows {
+            stmt.execute(params_from_iter(row))?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens `path` in exclusive locking mode and takes its write lock, which is only released
+    /// when the connection is closed.
+    fn lock(path: &str) -> Result<Connection> {
+        if let Some(dir) = Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE; COMMIT;")
+            .map_err(|e| format!("Lock shard {} error: {}", path, e))?;
+
+        Ok(conn)
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
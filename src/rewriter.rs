@@ -1,20 +1,335 @@
-use sqlparser::ast::Statement;
+use crate::{
+    parser::{Parser, Upsert},
+    Result, SqlParam,
+};
+use sqlparser::{
+    ast::{
+        ColumnOption, ColumnOptionDef, Expr, Function, FunctionArg, Ident, ObjectName, Query,
+        Select, SelectItem, SetExpr, Statement, Values,
+    },
+    tokenizer::Token,
+};
 
 pub struct ReWriter;
 
 impl ReWriter {
-    pub fn rewrite(ast: &Statement) -> String {
+    /// Rewrites a statement for the shards, `distinct_on_key` as in `Parser::get_query_from_ast`.
+    pub fn rewrite(ast: &Statement, upsert: Option<&Upsert>, distinct_on_key: bool) -> String {
         let mut new_ast = ast.clone();
         if let Statement::Query(query) = &mut new_ast {
             query.limit = None;
             query.offset = None;
-            log::trace!(
-                "Rewrite query '{}' -> '{}'",
-                ast.to_string(),
-                new_ast.to_string()
+
+            // Columns sorted by but not selected, an invalid ORDER BY fails when querying
+            let hidden = Parser::get_order_by(query)
+                .map(|(_, hidden)| hidden)
+                .unwrap_or_default();
+            if let SetExpr::Select(select) = &mut query.body {
+                // Values counted by DISTINCT may be on several shards, collect them instead
+                if let (false, Some(arg)) = (distinct_on_key, get_count_distinct_arg(select)) {
+                    select.distinct = true;
+                    select.projection = vec![SelectItem::UnnamedExpr(arg)];
+                    query.order_by.clear();
+                    log::trace!("Rewrite query '{}' -> '{}'", ast, new_ast);
+                    return Self::to_sql(&new_ast);
+                }
+
+                select.projection = select
+                    .projection
+                    .drain(..)
+                    .flat_map(split_average)
+                    .collect();
+                select
+                    .projection
+                    .extend(hidden.into_iter().map(SelectItem::UnnamedExpr));
+            }
+            log::trace!("Rewrite query '{}' -> '{}'", ast, new_ast);
+        }
+
+        // A column collation is not formatted by sqlparser, keep it as a column option
+        if let Statement::CreateTable { columns, .. } = &mut new_ast {
+            for column in columns {
+                if let Some(collation) = column.collation.take() {
+                    let tokens = vec![
+                        Token::make_keyword("COLLATE"),
+                        Token::make_word(&collation.to_string(), None),
+                    ];
+                    column.options.insert(
+                        0,
+                        ColumnOptionDef {
+                            name: None,
+                            option: ColumnOption::DialectSpecific(tokens),
+                        },
+                    );
+                }
+            }
+        }
+
+        Self::to_sql_with_upsert(&new_ast, upsert)
+    }
+
+    /// Formats the statement followed by its `ON CONFLICT` clause, if any.
+    pub fn to_sql_with_upsert(ast: &Statement, upsert: Option<&Upsert>) -> String {
+        match upsert {
+            Some(upsert) => Parser::restore_placeholders(&format!("{} {}", ast, upsert)),
+            None => Self::to_sql(ast),
+        }
+    }
+
+    /// Formats the statement with placeholders as `?NNN`.
+    pub fn to_sql(ast: &Statement) -> String {
+        Parser::restore_placeholders(&ast.to_string())
+    }
+
+    /// Keeps only the VALUES `rows` of an insert, placeholders are renumbered from `?1` and
+    /// the returned params only contain the values bound by those rows and the upsert.
+    pub fn rewrite_insert_rows(
+        ast: &Statement,
+        upsert: Option<&Upsert>,
+        rows: &[usize],
+        params: &[SqlParam],
+    ) -> Result<(String, Vec<SqlParam>)> {
+        let mut new_ast = ast.clone();
+        let values = match &mut new_ast {
+            Statement::Insert { source, .. } => match &mut source.body {
+                SetExpr::Values(values) => values,
+                _ => return Err("Expect VALUES in insert".into()),
+            },
+            _ => return Err("Expect insert statement".into()),
+        };
+
+        let mut new_rows = Vec::with_capacity(rows.len());
+        for row in rows {
+            new_rows.push(
+                values
+                    .0
+                    .get(*row)
+                    .ok_or(format!("Row {} not found in VALUES", row))?
+                    .clone(),
             );
         }
+        values.0 = new_rows;
+        let mut upsert = upsert.cloned();
+        let new_params = compact_placeholders(
+            values
+                .0
+                .iter_mut()
+                .flatten()
+                .chain(upsert.iter_mut().flat_map(|u| u.exprs_mut())),
+            params,
+        )?;
+
+        log::trace!("Rewrite insert rows {:?} -> '{}'", rows, new_ast);
+
+        Ok((
+            Self::to_sql_with_upsert(&new_ast, upsert.as_ref()),
+            new_params,
+        ))
+    }
+
+    /// Replaces the source of an insert by `rows` VALUES rows of `columns` placeholders each.
+    /// Placeholders of the upsert come first, the returned params only contain the values bound
+    /// by them, and VALUES placeholders follow row by row.
+    pub fn rewrite_insert_values(
+        ast: &Statement,
+        upsert: Option<&Upsert>,
+        rows: usize,
+        columns: usize,
+        params: &[SqlParam],
+    ) -> Result<(String, Vec<SqlParam>)> {
+        let mut upsert = upsert.cloned();
+        let new_params =
+            compact_placeholders(upsert.iter_mut().flat_map(|u| u.exprs_mut()), params)?;
+        let first = new_params.len() + 1;
+
+        let mut new_ast = ast.clone();
+        match &mut new_ast {
+            Statement::Insert { source, .. } => {
+                let values = (0..rows)
+                    .map(|row| {
+                        (0..columns)
+                            .map(|n| {
+                                Expr::Identifier(Parser::placeholder(first + row * columns + n))
+                            })
+                            .collect()
+                    })
+                    .collect();
+                **source = Query {
+                    with: None,
+                    body: SetExpr::Values(Values(values)),
+                    order_by: vec![],
+                    limit: None,
+                    offset: None,
+                    fetch: None,
+                };
+            }
+            _ => return Err("Expect insert statement".into()),
+        }
 
-        new_ast.to_string()
+        Ok((
+            Self::to_sql_with_upsert(&new_ast, upsert.as_ref()),
+            new_params,
+        ))
     }
 }
+
+/// Returns `x` if the projection is only `count(DISTINCT x)`.
+fn get_count_distinct_arg(select: &Select) -> Option<Expr> {
+    match select.projection.as_slice() {
+        [SelectItem::UnnamedExpr(Expr::Function(f))]
+        | [SelectItem::ExprWithAlias {
+            expr: Expr::Function(f),
+            ..
+        }] if f.distinct
+            && f.over.is_none()
+            && f.name.to_string().eq_ignore_ascii_case("count") =>
+        {
+            match f.args.as_slice() {
+                [FunctionArg::Unnamed(arg)] => Some(arg.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Replaces `avg(x)` by `total(x), count(x)`, which can be merged across shards unlike
+/// averages. `total` rather than `sum` as averages never fail on integer overflow.
+fn split_average(item: SelectItem) -> Vec<SelectItem> {
+    match &item {
+        SelectItem::UnnamedExpr(Expr::Function(f))
+        | SelectItem::ExprWithAlias {
+            expr: Expr::Function(f),
+            ..
+        } if f.name.to_string().eq_ignore_ascii_case("avg")
+            && f.args.len() == 1
+            && f.over.is_none() =>
+        {
+            let call = |name: &str| {
+                SelectItem::UnnamedExpr(Expr::Function(Function {
+                    name: ObjectName(vec![Ident::new(name)]),
+                    ..f.clone()
+                }))
+            };
+            vec![call("total"), call("count")]
+        }
+        _ => vec![item],
+    }
+}
+
+/// Renumbers the placeholders of `exprs` from `?1` in order of first appearance, returns the
+/// params they bind.
+fn compact_placeholders<'a>(
+    exprs: impl Iterator<Item = &'a mut Expr>,
+    params: &[SqlParam],
+) -> Result<Vec<SqlParam>> {
+    let mut numbers: Vec<usize> = vec![];
+    for expr in exprs {
+        visit_placeholders(expr, &mut |number| {
+            let position = match numbers.iter().position(|n| *n == number) {
+                Some(position) => position,
+                None => {
+                    numbers.push(number);
+                    numbers.len() - 1
+                }
+            };
+
+            position + 1
+        })?;
+    }
+
+    numbers
+        .iter()
+        .map(|n| {
+            n.checked_sub(1)
+                .and_then(|i| params.get(i))
+                .cloned()
+                .ok_or_else(|| format!("Param ?{} not found", n).into())
+        })
+        .collect()
+}
+
+/// Replaces the number of every placeholder in `expr` by the result of `f`, errors on
+/// expressions that can not be walked.
+fn visit_placeholders(expr: &mut Expr, f: &mut dyn FnMut(usize) -> usize) -> Result<()> {
+    if let Some(number) = Parser::get_placeholder(expr) {
+        *expr = Expr::Identifier(Parser::placeholder(f(number)));
+        return Ok(());
+    }
+
+    match expr {
+        Expr::Identifier(_)
+        | Expr::Value(_)
+        | Expr::TypedString { .. }
+        | Expr::CompoundIdentifier(_)
+        | Expr::Wildcard
+        | Expr::QualifiedWildcard(_) => {}
+        Expr::IsNull(e)
+        | Expr::IsNotNull(e)
+        | Expr::Nested(e)
+        | Expr::UnaryOp { expr: e, .. }
+        | Expr::Cast { expr: e, .. }
+        | Expr::TryCast { expr: e, .. }
+        | Expr::Extract { expr: e, .. }
+        | Expr::Collate { expr: e, .. }
+        | Expr::MapAccess { column: e, .. } => visit_placeholders(e, f)?,
+        Expr::BinaryOp { left, right, .. } => {
+            visit_placeholders(left, f)?;
+            visit_placeholders(right, f)?;
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            visit_placeholders(expr, f)?;
+            visit_placeholders(low, f)?;
+            visit_placeholders(high, f)?;
+        }
+        Expr::InList { expr, list, .. } => {
+            visit_placeholders(expr, f)?;
+            for e in list {
+                visit_placeholders(e, f)?;
+            }
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+        } => {
+            visit_placeholders(expr, f)?;
+            if let Some(e) = substring_from {
+                visit_placeholders(e, f)?;
+            }
+            if let Some(e) = substring_for {
+                visit_placeholders(e, f)?;
+            }
+        }
+        Expr::Function(func) if func.over.is_none() => {
+            for arg in &mut func.args {
+                match arg {
+                    FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => {
+                        visit_placeholders(arg, f)?
+                    }
+                }
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            if let Some(e) = operand {
+                visit_placeholders(e, f)?;
+            }
+            for e in conditions.iter_mut().chain(results.iter_mut()) {
+                visit_placeholders(e, f)?;
+            }
+            if let Some(e) = else_result {
+                visit_placeholders(e, f)?;
+            }
+        }
+        e => return Err(format!("Unsupported expression with placeholders: {}", e).into()),
+    }
+
+    Ok(())
+}
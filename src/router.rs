@@ -1,5 +1,12 @@
-use crate::{Result, ShardingIteConfig, SqlParam};
-use sqlparser::ast::{Expr, SetExpr, Statement};
+use crate::{
+    parser::{Parser, Upsert},
+    Result, ShardingIteConfig, ShardingRule, SqlParam,
+};
+use sqlparser::ast::{
+    BinaryOperator, Expr, FunctionArg, Ident, ObjectName, Query, SelectItem, SetExpr, Statement,
+    TableAlias, TableFactor, UnaryOperator, Value,
+};
+use std::collections::BTreeSet;
 
 pub struct Router {}
 
@@ -7,75 +14,732 @@ impl Router {
     pub fn get_indexes_with_params(
         config: &ShardingIteConfig,
         ast: &Statement,
-        params: &Vec<SqlParam>,
+        params: &[SqlParam],
     ) -> Result<Vec<u32>> {
-        if let Statement::Insert {
-            columns,
-            source,
-            table_name,
-            ..
-        } = ast
-        {
-            // Check table name
-            if table_name.0.len() == 0
-                || table_name.0.first().unwrap().value != config.sharding_table
-            {
+        match ast {
+            Statement::Insert { .. } => match Self::get_insert_rows_indexes(config, ast, params)? {
+                Some(rows) => {
+                    let routes: Vec<u32> = rows
+                        .into_iter()
+                        .collect::<BTreeSet<u32>>()
+                        .into_iter()
+                        .collect();
+                    log::trace!("Insert routes: {:?}", routes);
+                    Ok(routes)
+                }
+                None => Ok(Self::full_match(config)),
+            },
+            Statement::Query(query) => {
+                if let SetExpr::Select(select) = &query.body {
+                    // Reference tables hold the same rows in every shard
+                    let relations: Vec<&TableFactor> = select
+                        .from
+                        .iter()
+                        .flat_map(|t| {
+                            std::iter::once(&t.relation).chain(t.joins.iter().map(|j| &j.relation))
+                        })
+                        .collect();
+                    if !relations.is_empty()
+                        && relations.iter().all(|r| {
+                            matches!(r, TableFactor::Table { name, .. } if Self::is_reference_table(config, name))
+                        })
+                    {
+                        log::trace!("Reference tables, use first shard");
+                        return Ok(Self::full_match(config).into_iter().take(1).collect());
+                    }
+
+                    // Only a single table without joins can be pruned
+                    if let [table] = select.from.as_slice() {
+                        if let (TableFactor::Table { name, alias, .. }, true) =
+                            (&table.relation, table.joins.is_empty())
+                        {
+                            return Self::get_indexes_with_selection(
+                                config,
+                                name,
+                                alias.as_ref(),
+                                select.selection.as_ref(),
+                                params,
+                            );
+                        }
+                    }
+                }
+
+                log::trace!("Complex query, use full match");
+                Ok(Self::full_match(config))
+            }
+            Statement::Update {
+                table_name,
+                selection,
+                ..
+            }
+            | Statement::Delete {
+                table_name,
+                selection,
+            } => Self::get_indexes_with_selection(
+                config,
+                table_name,
+                None,
+                selection.as_ref(),
+                params,
+            ),
+            _ => {
+                log::trace!("Not dml, use full match");
+                Ok(Self::full_match(config))
+            }
+        }
+    }
+
+    /// Returns the shard of every row in the VALUES of an insert into the sharding table,
+    /// `None` means the insert is not routed by rows and goes to every shard.
+    pub fn get_insert_rows_indexes(
+        config: &ShardingIteConfig,
+        ast: &Statement,
+        params: &[SqlParam],
+    ) -> Result<Option<Vec<u32>>> {
+        let (table_name, columns, source) = match ast {
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+                ..
+            } => (table_name, columns, source),
+            _ => return Ok(None),
+        };
+
+        // Check table name
+        let rule = match Self::get_rule(config, table_name) {
+            Some(rule) => rule,
+            None => {
                 log::trace!("Table mismatch, use full match");
-                return Ok((0..config.sharding_count).collect());
+                return Ok(None);
             }
+        };
 
-            let sharding_column_index = columns
-                .iter()
-                .position(|s| s.value == config.sharding_column);
+        let mut indexes = Vec::with_capacity(rule.columns.len());
+        for column in &rule.columns {
+            match Self::get_column_position(columns, column) {
+                Some(index) => indexes.push(index),
+                None => {
+                    log::trace!("Sharding column not found in insert, use full match");
+                    return Ok(None);
+                }
+            }
+        }
+
+        let values = match &source.body {
+            SetExpr::Values(values) => values,
+            _ => {
+                log::trace!("Insert without values, use full match");
+                return Ok(None);
+            }
+        };
+
+        let mut routes = Vec::with_capacity(values.0.len());
+        for row in &values.0 {
+            let mut key = Vec::with_capacity(indexes.len());
+            for index in &indexes {
+                key.push(Self::get_insert_param(row.get(*index), params)?);
+            }
 
-            if let Some(index) = sharding_column_index {
-                if let SetExpr::Values(values) = &source.body {
-                    if values.0.len() != 1 {
-                        return Err("Values lenght is not 1".into());
+            routes.push(rule.strategy.index(&key, config.sharding_count)?);
+        }
+
+        log::trace!("Insert rows routes: {:?}", routes);
+        Ok(Some(routes))
+    }
+
+    fn get_insert_param(expr: Option<&Expr>, params: &[SqlParam]) -> Result<SqlParam> {
+        let expr = expr.ok_or("Sharding column not found in VALUES row")?;
+        Self::get_param(expr, params)?.ok_or_else(|| {
+            format!(
+                "Expect literal or placeholder of sharding column in VALUES, received {}",
+                Parser::restore_placeholders(&expr.to_string())
+            )
+            .into()
+        })
+    }
+
+    /// Routes by the selection on `table_name`, whose columns may be qualified by `alias` or
+    /// else by the table name.
+    fn get_indexes_with_selection(
+        config: &ShardingIteConfig,
+        table_name: &ObjectName,
+        alias: Option<&TableAlias>,
+        selection: Option<&Expr>,
+        params: &[SqlParam],
+    ) -> Result<Vec<u32>> {
+        let (rule, name) = match (
+            Self::get_rule(config, table_name),
+            Self::get_table_name(table_name),
+        ) {
+            (Some(rule), Some(name)) => (rule, name),
+            _ => {
+                log::trace!("Table mismatch, use full match");
+                return Ok(Self::full_match(config));
+            }
+        };
+        let qualifier = alias.map(|a| &a.name).unwrap_or(name).value.as_str();
+
+        let routes = match selection {
+            Some(expr) => Self::get_indexes_with_expr(config, rule, qualifier, expr, params)?,
+            None => None,
+        };
+
+        match routes {
+            Some(routes) if routes.is_empty() => {
+                // No row can match, one shard still answers with an empty result
+                log::trace!("Selection matches no shard, use first shard");
+                Ok(Self::full_match(config).into_iter().take(1).collect())
+            }
+            Some(routes) => {
+                let routes: Vec<u32> = routes.into_iter().collect();
+                log::trace!("Selection routes: {:?}", routes);
+                Ok(routes)
+            }
+            None => {
+                log::trace!("Sharding column is not pinned in selection, use full match");
+                Ok(Self::full_match(config))
+            }
+        }
+    }
+
+    /// Returns the shards that may contain rows matching `expr`, `None` means every shard.
+    ///
+    /// OR takes the union of both sides. Within an AND conjunction the key is pinned once every
+    /// key column is bound by an equality or IN list, range strategies are also narrowed by
+    /// comparisons and BETWEEN, and nested disjunctions intersect the result.
+    fn get_indexes_with_expr(
+        config: &ShardingIteConfig,
+        rule: &ShardingRule,
+        qualifier: &str,
+        expr: &Expr,
+        params: &[SqlParam],
+    ) -> Result<Option<BTreeSet<u32>>> {
+        match expr {
+            Expr::Nested(expr) => {
+                Self::get_indexes_with_expr(config, rule, qualifier, expr, params)
+            }
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Or,
+                right,
+            } => {
+                let left = Self::get_indexes_with_expr(config, rule, qualifier, left, params)?;
+                let right = Self::get_indexes_with_expr(config, rule, qualifier, right, params)?;
+
+                Ok(match (left, right) {
+                    (Some(l), Some(r)) => Some(l.union(&r).cloned().collect()),
+                    _ => None,
+                })
+            }
+            _ => {
+                let mut terms = vec![];
+                Self::flatten_and(expr, &mut terms);
+
+                let mut values: Vec<Option<Vec<SqlParam>>> = vec![None; rule.columns.len()];
+                let mut bounds: (Option<i64>, Option<i64>) = (None, None);
+                let mut indexes: Option<BTreeSet<u32>> = None;
+                for term in terms {
+                    if let Expr::BinaryOp {
+                        op: BinaryOperator::Or,
+                        ..
+                    } = term
+                    {
+                        let routes =
+                            Self::get_indexes_with_expr(config, rule, qualifier, term, params)?;
+                        indexes = Self::intersect(indexes, routes);
+                    } else if let Some((column, list)) =
+                        Self::get_column_values(rule, qualifier, term, params)?
+                    {
+                        // Any bound value list is a superset of the matching rows, keep the first
+                        values[column].get_or_insert(list);
+                    } else if let Some((lo, hi)) =
+                        Self::get_column_bounds(rule, qualifier, term, params)?
+                    {
+                        bounds.0 = bounds.0.max(lo);
+                        bounds.1 = match (bounds.1, hi) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
                     }
+                }
 
-                    if let Some(sharding_column_value) = values.0[0].get(index) {
-                        match sharding_column_value {
-                            Expr::Identifier(id) => {
-                                if id.value.starts_with("?") {
-                                    match params.get(index) {
-                                        Some(param) => {
-                                            let routes = vec![config.sharding_index.as_ref()(
-                                                param,
-                                            )?];
-                                            log::trace!("Param routes: {:?}", routes);
-                                            return Ok(routes)
-                                        }
-                                        None => return Err("Sharding param not found".into()),
-                                    }
-                                } else {
-                                    return Err(format!(
-                                        "Ident must starts with ?, received {:?}",
-                                        id
-                                    )
-                                    .into());
-                                }
-                            }
-                            Expr::Value(v) => {
-                                match v {
-                                    sqlparser::ast::Value::Number(n, ..) => {
-                                        let n: i64 = n.parse().map_err(|e| format!("Parse number error in sql router: {}", e))?;
-                                        let param = SqlParam::I64(n);
-                                        let routes = vec![config.sharding_index.as_ref()(&param)?];
-                                        log::trace!("Sql values routes: {:?}", routes);
-                                        return Ok(routes);
-                                    },
-                                    e @ _ => return Err(format!("Expect number type of sharding column in VALUES, received {:?}", e).into()),
-                                }
-                            }
-                            e @ _ => return Err(format!("Expect id or value, received {:?}", e).into()),
-                        }
+                if bounds != (None, None) {
+                    let routes = rule.strategy.range_indexes(bounds.0, bounds.1);
+                    indexes = Self::intersect(indexes, routes);
+                }
+
+                if values.iter().all(Option::is_some) {
+                    let mut keys: Vec<Vec<SqlParam>> = vec![vec![]];
+                    for list in values.into_iter().flatten() {
+                        keys = keys
+                            .iter()
+                            .flat_map(|key| {
+                                list.iter().map(move |v| {
+                                    let mut key = key.clone();
+                                    key.push(v.clone());
+                                    key
+                                })
+                            })
+                            .collect();
+                    }
+
+                    // A key the strategy can not route may still match stored rows, for
+                    // example '2' in an INTEGER column, so it falls back to every shard
+                    let routes: Result<BTreeSet<u32>> = keys
+                        .iter()
+                        .map(|key| rule.strategy.index(key, config.sharding_count))
+                        .collect();
+                    match routes {
+                        Ok(routes) => indexes = Self::intersect(indexes, Some(routes)),
+                        Err(e) => log::trace!("Key is not routable, use full match: {}", e),
                     }
                 }
+
+                Ok(indexes)
             }
         }
+    }
+
+    fn flatten_and<'a>(expr: &'a Expr, terms: &mut Vec<&'a Expr>) {
+        match expr {
+            Expr::Nested(expr) => Self::flatten_and(expr, terms),
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => {
+                Self::flatten_and(left, terms);
+                Self::flatten_and(right, terms);
+            }
+            expr => terms.push(expr),
+        }
+    }
+
+    fn intersect(a: Option<BTreeSet<u32>>, b: Option<BTreeSet<u32>>) -> Option<BTreeSet<u32>> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.intersection(&b).cloned().collect()),
+            (Some(a), None) => Some(a),
+            (None, b) => b,
+        }
+    }
+
+    /// Returns the key column bound by an equality or IN list and its possible values.
+    fn get_column_values(
+        rule: &ShardingRule,
+        qualifier: &str,
+        expr: &Expr,
+        params: &[SqlParam],
+    ) -> Result<Option<(usize, Vec<SqlParam>)>> {
+        match expr {
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } => {
+                let column = match Self::get_key_column(rule, qualifier, expr) {
+                    Some(column) => column,
+                    None => return Ok(None),
+                };
+
+                let mut values = Vec::with_capacity(list.len());
+                for value in list {
+                    match Self::get_param(value, params)? {
+                        Some(param) => values.push(param),
+                        None => return Ok(None),
+                    }
+                }
+
+                Ok(Some((column, values)))
+            }
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => {
+                let (column, value) = match (
+                    Self::get_key_column(rule, qualifier, left),
+                    Self::get_key_column(rule, qualifier, right),
+                ) {
+                    (Some(column), _) => (column, right),
+                    (None, Some(column)) => (column, left),
+                    _ => return Ok(None),
+                };
+
+                Ok(Self::get_param(value, params)?.map(|param| (column, vec![param])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the inclusive `(low, high)` bounds a comparison or BETWEEN puts on the single key
+    /// column, only integer bounds are used.
+    fn get_column_bounds(
+        rule: &ShardingRule,
+        qualifier: &str,
+        expr: &Expr,
+        params: &[SqlParam],
+    ) -> Result<Option<(Option<i64>, Option<i64>)>> {
+        if rule.columns.len() != 1 {
+            return Ok(None);
+        }
+
+        let get_i64 = |expr: &Expr| -> Result<Option<i64>> {
+            Ok(match Self::get_param(expr, params)? {
+                Some(SqlParam::I64(n)) => Some(n),
+                Some(SqlParam::U32(n)) => Some(n as i64),
+                Some(SqlParam::U16(n)) => Some(n as i64),
+                _ => None,
+            })
+        };
+
+        match expr {
+            Expr::Between {
+                expr,
+                negated: false,
+                low,
+                high,
+            } if Self::get_key_column(rule, qualifier, expr).is_some() => {
+                Ok(Some((get_i64(low)?, get_i64(high)?)))
+            }
+            Expr::BinaryOp { left, op, right } => {
+                // Normalize to `column op value`
+                let (op, value) = if Self::get_key_column(rule, qualifier, left).is_some() {
+                    (op.clone(), right)
+                } else if Self::get_key_column(rule, qualifier, right).is_some() {
+                    let op = match op {
+                        BinaryOperator::Lt => BinaryOperator::Gt,
+                        BinaryOperator::LtEq => BinaryOperator::GtEq,
+                        BinaryOperator::Gt => BinaryOperator::Lt,
+                        BinaryOperator::GtEq => BinaryOperator::LtEq,
+                        _ => return Ok(None),
+                    };
+                    (op, left)
+                } else {
+                    return Ok(None);
+                };
+
+                let n = match get_i64(value)? {
+                    Some(n) => n,
+                    None => return Ok(None),
+                };
+                Ok(match op {
+                    BinaryOperator::Lt => Some((None, Some(n.saturating_sub(1)))),
+                    BinaryOperator::LtEq => Some((None, Some(n))),
+                    BinaryOperator::Gt => Some((Some(n.saturating_add(1)), None)),
+                    BinaryOperator::GtEq => Some((Some(n), None)),
+                    _ => None,
+                })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves a literal or placeholder expression, `None` means the value is unknown.
+    fn get_param(expr: &Expr, params: &[SqlParam]) -> Result<Option<SqlParam>> {
+        if let Some(number) = Parser::get_placeholder(expr) {
+            return match params.get(number - 1) {
+                Some(param) => Ok(Some(param.clone())),
+                None => Err(format!("Sharding param ?{} not found", number).into()),
+            };
+        }
+
+        match expr {
+            Expr::Nested(expr) => Self::get_param(expr, params),
+            Expr::UnaryOp {
+                op: UnaryOperator::Plus,
+                expr,
+            } => match expr.as_ref() {
+                Expr::Value(Value::Number(n, ..)) => Ok(Self::parse_number(n)),
+                _ => Ok(None),
+            },
+            Expr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr,
+            } => match expr.as_ref() {
+                Expr::Value(Value::Number(n, ..)) => Ok(Self::parse_number(&format!("-{}", n))),
+                _ => Ok(None),
+            },
+            Expr::Value(value) => Ok(match value {
+                Value::Number(n, ..) => Self::parse_number(n),
+                Value::SingleQuotedString(s) | Value::NationalStringLiteral(s) => {
+                    Some(SqlParam::String(s.to_string()))
+                }
+                Value::HexStringLiteral(h) => Some(SqlParam::Blob(Self::parse_hex(h)?)),
+                Value::Boolean(b) => Some(SqlParam::I64(*b as i64)),
+                Value::Null => Some(SqlParam::Null),
+                _ => None,
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_number(n: &str) -> Option<SqlParam> {
+        match n.parse::<i64>() {
+            Ok(n) => Some(SqlParam::I64(n)),
+            Err(_) => n.parse::<f64>().ok().map(SqlParam::F64),
+        }
+    }
+
+    fn parse_hex(h: &str) -> Result<Vec<u8>> {
+        if !h.len().is_multiple_of(2) || !h.is_ascii() {
+            return Err(format!("Invalid blob literal X'{}'", h).into());
+        }
+
+        (0..h.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&h[i..i + 2], 16)
+                    .map_err(|e| format!("Invalid blob literal X'{}': {}", h, e).into())
+            })
+            .collect()
+    }
+
+    /// Returns true if every `count(DISTINCT x)` of a query counts the sharding key of its only
+    /// table. A single key column never holds a value on two shards, so the shard counts add up.
+    pub fn is_distinct_on_key(config: &ShardingIteConfig, ast: &Statement) -> bool {
+        let select = match ast {
+            Statement::Query(query) => match &query.body {
+                SetExpr::Select(select) => select,
+                _ => return false,
+            },
+            _ => return false,
+        };
+        let (table_name, alias) = match select.from.as_slice() {
+            [table] if table.joins.is_empty() => match &table.relation {
+                TableFactor::Table { name, alias, .. } => (name, alias),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        let (rule, name) = match (
+            Self::get_rule(config, table_name),
+            Self::get_table_name(table_name),
+        ) {
+            (Some(rule), Some(name)) if rule.columns.len() == 1 => (rule, name),
+            _ => return false,
+        };
+        let qualifier = alias
+            .as_ref()
+            .map(|a| &a.name)
+            .unwrap_or(name)
+            .value
+            .as_str();
+
+        select.projection.iter().all(|item| match item {
+            SelectItem::UnnamedExpr(Expr::Function(f))
+            | SelectItem::ExprWithAlias {
+                expr: Expr::Function(f),
+                ..
+            } if f.distinct => matches!(
+                f.args.as_slice(),
+                [FunctionArg::Unnamed(arg)] if Self::get_key_column(rule, qualifier, arg).is_some()
+            ),
+            _ => true,
+        })
+    }
+
+    /// Returns the rule of an INSERT ... SELECT into a sharding table, whose rows must be routed
+    /// one by one.
+    pub fn get_insert_select<'a>(
+        config: &'a ShardingIteConfig,
+        ast: &Statement,
+    ) -> Option<&'a ShardingRule> {
+        match ast {
+            Statement::Insert {
+                table_name, source, ..
+            } if !matches!(source.body, SetExpr::Values(_)) => Self::get_rule(config, table_name),
+            _ => None,
+        }
+    }
+
+    /// Returns the shards to read the source of an INSERT ... SELECT from. A source without
+    /// sharding tables holds the same rows on every shard and is read from the first one.
+    pub fn get_insert_select_indexes(
+        config: &ShardingIteConfig,
+        source: &Query,
+        params: &[SqlParam],
+    ) -> Result<Vec<u32>> {
+        if !Self::has_sharding_table(config, source) {
+            log::trace!("Source without sharding tables, use first shard");
+            return Ok(Self::full_match(config).into_iter().take(1).collect());
+        }
+
+        let select = Statement::Query(Box::new(source.clone()));
+        let list = Self::get_indexes_with_params(config, &select, params)?;
+        if list.len() > 1 && !Self::is_concatenable(source) {
+            return Err(
+                "INSERT ... SELECT with LIMIT, DISTINCT, GROUP BY or aggregates across shards is not supported"
+                    .into(),
+            );
+        }
+
+        Ok(list)
+    }
+
+    /// Returns true if a table of the FROM clauses of `query`, including joins and derived
+    /// tables, is a sharding table. Other table factors are assumed to be.
+    fn has_sharding_table(config: &ShardingIteConfig, query: &Query) -> bool {
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return true,
+        };
+
+        select.from.iter().any(|t| {
+            std::iter::once(&t.relation)
+                .chain(t.joins.iter().map(|j| &j.relation))
+                .any(|r| match r {
+                    TableFactor::Table { name, .. } => Self::get_rule(config, name).is_some(),
+                    TableFactor::Derived { subquery, .. } => {
+                        Self::has_sharding_table(config, subquery)
+                    }
+                    _ => true,
+                })
+        })
+    }
+
+    /// Returns true if the rows of `query` on several shards are simply the union of the rows of
+    /// each shard, that is without LIMIT, DISTINCT, GROUP BY, aggregates or window functions.
+    pub fn is_concatenable(query: &Query) -> bool {
+        if query.limit.is_some() || query.offset.is_some() || query.fetch.is_some() {
+            return false;
+        }
+
+        match &query.body {
+            SetExpr::Select(select) => {
+                !select.distinct
+                    && select.group_by.is_empty()
+                    && select.having.is_none()
+                    && select.projection.iter().all(|item| match item {
+                        SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => {
+                            !Parser::has_aggregate(e) && !Parser::has_window_function(e)
+                        }
+                        _ => true,
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the rule of an UPDATE which assigns one of its sharding key columns.
+    pub fn get_key_update<'a>(
+        config: &'a ShardingIteConfig,
+        ast: &Statement,
+    ) -> Option<&'a ShardingRule> {
+        match ast {
+            Statement::Update {
+                table_name,
+                assignments,
+                ..
+            } => Self::get_rule(config, table_name).filter(|rule| {
+                assignments.iter().any(|a| {
+                    rule.columns
+                        .iter()
+                        .any(|c| Self::is_same_name(c, &a.id.value))
+                })
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the rule of an INSERT whose `ON CONFLICT DO UPDATE` assigns one of its sharding
+    /// key columns, such rows can not be moved.
+    pub fn get_upsert_key_update<'a>(
+        config: &'a ShardingIteConfig,
+        ast: &Statement,
+        upsert: Option<&Upsert>,
+    ) -> Option<&'a ShardingRule> {
+        match (ast, upsert.and_then(|u| u.update.as_ref())) {
+            (Statement::Insert { table_name, .. }, Some((assignments, _))) => {
+                Self::get_rule(config, table_name).filter(|rule| {
+                    assignments.iter().any(|a| {
+                        rule.columns
+                            .iter()
+                            .any(|c| Self::is_same_name(c, &a.id.value))
+                    })
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true for writes to a reference table, which must succeed on every shard.
+    pub fn is_reference_write(config: &ShardingIteConfig, ast: &Statement) -> bool {
+        match ast {
+            Statement::Insert { table_name, .. }
+            | Statement::Update { table_name, .. }
+            | Statement::Delete { table_name, .. } => Self::is_reference_table(config, table_name),
+            _ => false,
+        }
+    }
+
+    fn is_reference_table(config: &ShardingIteConfig, table_name: &ObjectName) -> bool {
+        match Self::get_table_name(table_name) {
+            Some(name) => config
+                .reference_tables
+                .iter()
+                .any(|t| Self::is_same_name(t, &name.value)),
+            None => false,
+        }
+    }
+
+    fn get_rule<'a>(
+        config: &'a ShardingIteConfig,
+        table_name: &ObjectName,
+    ) -> Option<&'a ShardingRule> {
+        let name = Self::get_table_name(table_name)?;
+        config
+            .sharding_rules
+            .iter()
+            .find(|r| Self::is_same_name(&r.table, &name.value))
+    }
+
+    /// Returns the table name without the `main` schema, `None` for tables of other schemas
+    /// which are not sharded.
+    fn get_table_name(table_name: &ObjectName) -> Option<&Ident> {
+        match table_name.0.as_slice() {
+            [name] => Some(name),
+            [schema, name] if Self::is_same_name(&schema.value, "main") => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Identifiers are compared like SQLite does, ignoring ASCII case whether quoted or not.
+    pub fn is_same_name(a: &str, b: &str) -> bool {
+        a.eq_ignore_ascii_case(b)
+    }
+
+    /// Returns the position of `column` in the column list of an insert.
+    pub fn get_column_position(columns: &[Ident], column: &str) -> Option<usize> {
+        columns
+            .iter()
+            .position(|id| Self::is_same_name(&id.value, column))
+    }
+
+    /// Returns the position of the key column named by `expr`, a qualified column must be
+    /// qualified by `qualifier`, optionally preceded by the `main` schema.
+    fn get_key_column(rule: &ShardingRule, qualifier: &str, expr: &Expr) -> Option<usize> {
+        let column = match expr {
+            Expr::Identifier(id) => id,
+            Expr::CompoundIdentifier(ids) => match ids.as_slice() {
+                [table, column] if Self::is_same_name(&table.value, qualifier) => column,
+                [schema, table, column]
+                    if Self::is_same_name(&schema.value, "main")
+                        && Self::is_same_name(&table.value, qualifier) =>
+                {
+                    column
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        rule.columns
+            .iter()
+            .position(|c| Self::is_same_name(c, &column.value))
+    }
 
-        log::trace!("Not insert, use full match");
-        Ok((0..config.sharding_count).collect())
+    fn full_match(config: &ShardingIteConfig) -> Vec<u32> {
+        (0..config.sharding_count).collect()
     }
 }
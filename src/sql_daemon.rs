@@ -1,8 +1,13 @@
 use crate::Result;
 use rusqlite::{types::ValueRef, Connection};
-use std::sync::{
-    mpsc::{Receiver, Sender},
-    Arc,
+use std::{
+    cmp::Ordering,
+    fmt,
+    panic::RefUnwindSafe,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
 };
 
 #[derive(Debug, Clone)]
@@ -11,6 +16,9 @@ pub enum SqlParam {
     I64(i64),
     U32(u32),
     U16(u16),
+    F64(f64),
+    Blob(Vec<u8>),
+    Null,
 }
 
 impl rusqlite::ToSql for SqlParam {
@@ -20,10 +28,46 @@ impl rusqlite::ToSql for SqlParam {
             SqlParam::U32(v) => Ok(rusqlite::types::ToSqlOutput::Owned((*v).into())),
             SqlParam::U16(v) => Ok(rusqlite::types::ToSqlOutput::Owned((*v).into())),
             SqlParam::String(v) => Ok(rusqlite::types::ToSqlOutput::Owned(v.to_string().into())),
+            SqlParam::F64(v) => Ok(rusqlite::types::ToSqlOutput::Owned((*v).into())),
+            SqlParam::Blob(v) => Ok(rusqlite::types::ToSqlOutput::Borrowed(
+                rusqlite::types::ValueRef::Blob(v),
+            )),
+            SqlParam::Null => Ok(rusqlite::types::ToSqlOutput::Owned(
+                rusqlite::types::Value::Null,
+            )),
         }
     }
 }
 
+/// Comparison function of a user collation, registered on every shard connection and used by
+/// the merge of `Rows`.
+#[derive(Clone)]
+pub struct Collation(Arc<CollationFn>);
+
+type CollationFn = dyn Fn(&str, &str) -> Ordering + Send + Sync + RefUnwindSafe;
+
+impl Collation {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&str, &str) -> Ordering + Send + Sync + RefUnwindSafe + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.0.as_ref()(a, b)
+    }
+}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Collation")
+    }
+}
+
+/// Column names and rows of an ad-hoc query.
+pub type SqlRows = (Vec<String>, Vec<Vec<SqlValue>>);
+
 #[derive(Debug, Clone)]
 pub enum SqlValue {
     Null,
@@ -33,6 +77,48 @@ pub enum SqlValue {
     Blob(Vec<u8>),
 }
 
+impl SqlValue {
+    /// Compares like SQLite does without collation: NULL < numbers < text < blob, integers and
+    /// reals compare numerically, text and blobs bytewise.
+    pub fn sqlite_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::Real(a), Self::Real(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (Self::Integer(a), Self::Real(b)) => cmp_integer_real(*a, *b),
+            (Self::Real(a), Self::Integer(b)) => cmp_integer_real(*b, *a).reverse(),
+            (Self::Text(a), Self::Text(b)) | (Self::Blob(a), Self::Blob(b)) => a.cmp(b),
+            (a, b) => a.type_rank().cmp(&b.type_rank()),
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::Integer(_) | Self::Real(_) => 1,
+            Self::Text(_) => 2,
+            Self::Blob(_) => 3,
+        }
+    }
+}
+
+/// Compares without converting the integer to a real, which would lose precision above 2^53.
+fn cmp_integer_real(a: i64, b: f64) -> Ordering {
+    if b.is_nan() {
+        return Ordering::Equal;
+    }
+    if b < i64::MIN as f64 {
+        return Ordering::Greater;
+    }
+    if b >= i64::MAX as f64 {
+        return Ordering::Less;
+    }
+
+    match a.cmp(&(b as i64)) {
+        Ordering::Equal => 0f64.partial_cmp(&b.fract()).unwrap_or(Ordering::Equal),
+        ordering => ordering,
+    }
+}
+
 impl From<rusqlite::types::ValueRef<'_>> for SqlValue {
     fn from(val: rusqlite::types::ValueRef) -> Self {
         match val {
@@ -45,9 +131,21 @@ impl From<rusqlite::types::ValueRef<'_>> for SqlValue {
     }
 }
 
-impl<'a> Into<ValueRef<'a>> for &'a SqlValue {
-    fn into(self) -> ValueRef<'a> {
-        match &self {
+impl From<SqlValue> for SqlParam {
+    fn from(val: SqlValue) -> Self {
+        match val {
+            SqlValue::Null => Self::Null,
+            SqlValue::Integer(v) => Self::I64(v),
+            SqlValue::Real(v) => Self::F64(v),
+            SqlValue::Text(v) => Self::String(String::from_utf8_lossy(&v).to_string()),
+            SqlValue::Blob(v) => Self::Blob(v),
+        }
+    }
+}
+
+impl<'a> From<&'a SqlValue> for ValueRef<'a> {
+    fn from(val: &'a SqlValue) -> Self {
+        match &val {
             SqlValue::Null => ValueRef::Null,
             SqlValue::Integer(v) => ValueRef::Integer(*v),
             SqlValue::Real(v) => ValueRef::Real(*v),
@@ -62,7 +160,9 @@ pub enum DataCall {
     Exit,
     Prepare(String),
     StatementExecute(Arc<Vec<SqlParam>>),
+    StatementExecuteSql(String, Arc<Vec<SqlParam>>),
     StatementQuery(Arc<Vec<SqlParam>>),
+    StatementQuerySql(String, Arc<Vec<SqlParam>>),
     StatementEnd,
     RowsNext,
     RowsEnd,
@@ -70,6 +170,7 @@ pub enum DataCall {
     TransactionCommit,
     TransactionRollback,
     LastInsertRowId,
+    CreateCollation(String, Collation),
 }
 
 #[derive(Debug)]
@@ -77,11 +178,13 @@ pub enum DataRet {
     Prepare(rusqlite::Result<()>),
     StatementExecute(rusqlite::Result<()>),
     StatementQuery(rusqlite::Result<()>),
+    StatementQuerySql(rusqlite::Result<SqlRows>),
     Next(rusqlite::Result<Option<Vec<SqlValue>>>),
     Transaction(rusqlite::Result<()>),
     TransactionCommit(rusqlite::Result<()>),
     TransactionRollback(rusqlite::Result<()>),
     LastInsertRowId(i64),
+    CreateCollation(rusqlite::Result<()>),
 }
 
 pub struct SqlDaemon {
@@ -126,7 +229,14 @@ impl SqlDaemon {
                     log::trace!("[{}] Last insert row id", self.index);
                     process_last_insert_row_id(self.index, &self.tx, &mut self.conn)?;
                 }
-                d @ _ => log::error!("DataCall mismatch, received: {:?}", d),
+                DataCall::CreateCollation(name, collation) => {
+                    log::trace!("[{}] Create collation: {}", self.index, name);
+                    let ret = self
+                        .conn
+                        .create_collation(&name, move |a, b| collation.compare(a, b));
+                    self.tx.send((self.index, DataRet::CreateCollation(ret)))?;
+                }
+                d => log::error!("DataCall mismatch, received: {:?}", d),
             }
         }
     }
@@ -179,7 +289,7 @@ fn process_transcation(
                 }
                 break;
             }
-            d @ _ => {
+            d => {
                 log::error!("DataCall mismatch in transcation, received: {:?}", d);
             }
         }
@@ -195,7 +305,8 @@ fn process_transcation_prepare(
     tc: &mut rusqlite::Transaction,
     sql: String,
 ) -> Result<()> {
-    let mut stmt = match tc.prepare(&sql) {
+    let conn: &Connection = tc;
+    let mut stmt = match conn.prepare(&sql) {
         Ok(stmt) => {
             tx.send((index, DataRet::Prepare(Ok(()))))?;
             stmt
@@ -206,7 +317,7 @@ fn process_transcation_prepare(
         }
     };
 
-    process_statement(index, tx, rx, &mut stmt)?;
+    process_statement(index, tx, rx, conn, &mut stmt)?;
 
     Ok(())
 }
@@ -218,6 +329,7 @@ fn process_prepare(
     conn: &mut rusqlite::Connection,
     sql: String,
 ) -> Result<()> {
+    let conn: &Connection = conn;
     let mut stmt = match conn.prepare(&sql) {
         Ok(stmt) => {
             tx.send((index, DataRet::Prepare(Ok(()))))?;
@@ -229,7 +341,7 @@ fn process_prepare(
         }
     };
 
-    process_statement(index, tx, rx, &mut stmt)?;
+    process_statement(index, tx, rx, conn, &mut stmt)?;
 
     Ok(())
 }
@@ -238,6 +350,7 @@ fn process_statement(
     index: u32,
     tx: &Sender<(u32, DataRet)>,
     rx: &Receiver<DataCall>,
+    conn: &Connection,
     stmt: &mut rusqlite::Statement,
 ) -> Result<()> {
     loop {
@@ -253,6 +366,17 @@ fn process_statement(
                     }
                 }
             }
+            DataCall::StatementExecuteSql(sql, params) => {
+                log::trace!("[{}] Statement execute sql: {}, {:?}", index, sql, params);
+                match conn.execute(&sql, &*param_vec_to_tosql_vec(&params)) {
+                    Ok(_) => {
+                        tx.send((index, DataRet::StatementExecute(Ok(()))))?;
+                    }
+                    Err(e) => {
+                        tx.send((index, DataRet::StatementExecute(Err(e))))?;
+                    }
+                }
+            }
             DataCall::StatementQuery(params) => {
                 log::trace!("[{}] Statement query: {:?}", index, params);
                 let mut rows = match stmt.query(&*param_vec_to_tosql_vec(&params)) {
@@ -268,11 +392,16 @@ fn process_statement(
 
                 process_rows(index, tx, rx, &mut rows)?;
             }
+            DataCall::StatementQuerySql(sql, params) => {
+                log::trace!("[{}] Statement query sql: {}, {:?}", index, sql, params);
+                let ret = query_sql(conn, &sql, &params);
+                tx.send((index, DataRet::StatementQuerySql(ret)))?;
+            }
             DataCall::StatementEnd => {
                 log::trace!("[{}] Statement end", index);
                 break;
             }
-            d @ _ => {
+            d => {
                 log::error!("DataCall mismatch in statement, received: {:?}", d);
             }
         }
@@ -281,6 +410,24 @@ fn process_statement(
     Ok(())
 }
 
+/// Runs an ad-hoc query and collects all of its rows.
+fn query_sql(conn: &Connection, sql: &str, params: &[SqlParam]) -> rusqlite::Result<SqlRows> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query(&*param_vec_to_tosql_vec(params))?;
+
+    let mut values = vec![];
+    while let Some(row) = rows.next()? {
+        values.push(
+            (0..columns.len())
+                .map(|i| row.get_ref_unwrap(i).into())
+                .collect(),
+        );
+    }
+
+    Ok((columns, values))
+}
+
 fn process_rows(
     index: u32,
     tx: &Sender<(u32, DataRet)>,
@@ -316,7 +463,7 @@ fn process_rows(
                 log::trace!("[{}] Rows end", index);
                 break;
             }
-            d @ _ => {
+            d => {
                 log::error!("DataCall mismatch in rows, received: {:?}", d);
             }
         }
@@ -336,6 +483,6 @@ fn process_last_insert_row_id(
     Ok(())
 }
 
-fn param_vec_to_tosql_vec(v: &Vec<SqlParam>) -> Vec<&dyn rusqlite::ToSql> {
+fn param_vec_to_tosql_vec(v: &[SqlParam]) -> Vec<&dyn rusqlite::ToSql> {
     v.iter().map(|p| p as &dyn rusqlite::ToSql).collect()
 }
@@ -0,0 +1,375 @@
+use crate::{Result, SqlParam};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+pub type ShardingIndex = Box<dyn Fn(&[SqlParam]) -> Result<u32> + Send>;
+
+/// Maps the value of a sharding column to a shard index.
+pub enum ShardingStrategy {
+    /// Integer value modulo the sharding count, negative values wrap around
+    Modulo,
+    /// CRC32 of the value modulo the sharding count, integers are hashed as their decimal text
+    /// so `2` and `'2'` route alike, strings as their UTF-8 bytes, blobs as is and NULL as no
+    /// bytes, composite keys prefix each value by its length
+    Hash,
+    /// Ranges as `(start, index)` with strictly increasing starts, each range owns
+    /// `[start, next start)` and the last one is unbounded, values below the first start are
+    /// rejected
+    Range(Vec<(i64, u32)>),
+    /// Explicit map from value to shard, integers are looked up by their decimal form
+    Lookup(HashMap<String, u32>),
+    /// CRC32 of the value picks one of a fixed number of virtual buckets and the bucket map
+    /// picks the shard, so changing the sharding count only moves reassigned buckets
+    Bucket(BucketMap),
+    /// Escape hatch for everything else
+    Custom(ShardingIndex),
+}
+
+impl ShardingStrategy {
+    /// Returns the shard of the key `params`, one value per key column, errors if the key can
+    /// not be routed or the result is not below `count`.
+    ///
+    /// Only `Hash`, `Bucket` and `Custom` accept composite keys.
+    pub fn index(&self, params: &[SqlParam], count: u32) -> Result<u32> {
+        let single = || match params {
+            [param] => Ok(param),
+            _ => Err(format!(
+                "Strategy expects a single key column, received {:?}",
+                params
+            )),
+        };
+
+        let index = match self {
+            Self::Modulo => match as_i64(single()?) {
+                Some(n) => n.rem_euclid(count as i64) as u32,
+                None => {
+                    return Err(format!(
+                        "Modulo strategy expects an integer, received {:?}",
+                        params
+                    )
+                    .into())
+                }
+            },
+            Self::Hash => crc32(&key_bytes(params)) % count,
+            Self::Range(ranges) => {
+                let n = as_i64(single()?).ok_or_else(|| {
+                    format!("Range strategy expects an integer, received {:?}", params)
+                })?;
+                match ranges.iter().rev().find(|(start, _)| *start <= n) {
+                    Some((_, index)) => *index,
+                    None => return Err(format!("Value {} is below every range", n).into()),
+                }
+            }
+            Self::Lookup(map) => {
+                let key = match single()? {
+                    SqlParam::String(s) => s.to_string(),
+                    p => as_i64(p).map(|n| n.to_string()).ok_or_else(|| {
+                        format!(
+                            "Lookup strategy expects an integer or string, received {:?}",
+                            p
+                        )
+                    })?,
+                };
+                *map.get(&key)
+                    .ok_or_else(|| format!("Value {:?} not found in lookup map", params))?
+            }
+            Self::Bucket(map) => {
+                let bucket = map.bucket(params);
+                map.shard(bucket)
+                    .ok_or_else(|| format!("Bucket {} is not assigned to a shard", bucket))?
+            }
+            Self::Custom(f) => f.as_ref()(params)?,
+        };
+
+        if index >= count {
+            return Err(format!(
+                "Sharding index {} out of range, sharding count is {}",
+                index, count
+            )
+            .into());
+        }
+
+        Ok(index)
+    }
+
+    /// Checks the strategy can route at all, ranges must have strictly increasing starts.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Self::Range(ranges) = self {
+            if let Some(pair) = ranges.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+                return Err(format!(
+                    "Range starts must be strictly increasing, received {} then {}",
+                    pair[0].0, pair[1].0
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the shards owning the inclusive interval `[lo, hi]`, `None` if the strategy
+    /// can not tell.
+    pub(crate) fn range_indexes(&self, lo: Option<i64>, hi: Option<i64>) -> Option<BTreeSet<u32>> {
+        let ranges = match self {
+            Self::Range(ranges) => ranges,
+            _ => return None,
+        };
+
+        let lo = lo.unwrap_or(i64::MIN);
+        let hi = hi.unwrap_or(i64::MAX);
+        let mut indexes = BTreeSet::new();
+        for (i, (start, index)) in ranges.iter().enumerate() {
+            let end = ranges
+                .get(i + 1)
+                .map(|(next, _)| next - 1)
+                .unwrap_or(i64::MAX);
+            if *start <= hi && end >= lo {
+                indexes.insert(*index);
+            }
+        }
+
+        Some(indexes)
+    }
+}
+
+/// Assignment of virtual buckets to shards.
+///
+/// The map is stored as text next to the shard files, the first line is the sharding count and
+/// each following line is the shard of one bucket.
+#[derive(Debug, Clone)]
+pub struct BucketMap {
+    buckets: u32,
+    sharding_count: u32,
+    shards: Vec<u32>,
+}
+
+impl BucketMap {
+    /// Creates an unassigned map of `buckets` buckets, it is loaded or created by
+    /// `ShardingIte::new`. Errors if `buckets` is 0.
+    pub fn new(buckets: u32) -> Result<Self> {
+        if buckets == 0 {
+            return Err("Bucket map needs at least one bucket".into());
+        }
+
+        Ok(Self {
+            buckets,
+            sharding_count: 0,
+            shards: vec![],
+        })
+    }
+
+    /// Returns the file of the bucket map of `table`, in the directory of `sharding_path`.
+    pub fn path_of(sharding_path: &str, table: &str) -> PathBuf {
+        let dir = Path::new(sharding_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        dir.join(format!("{}.buckets", table))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Read bucket map {:?} error: {}", path, e))?;
+        let mut lines = content.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let sharding_count: u32 = lines
+            .next()
+            .ok_or_else(|| format!("Bucket map {:?} is empty", path))?
+            .parse()?;
+        let shards = lines
+            .map(|l| l.parse::<u32>())
+            .collect::<std::result::Result<Vec<u32>, _>>()?;
+        if shards.is_empty() {
+            return Err(format!("Bucket map {:?} has no buckets", path).into());
+        }
+        if let Some(shard) = shards.iter().find(|s| **s >= sharding_count) {
+            return Err(format!(
+                "Shard {} in bucket map {:?} out of range, sharding count is {}",
+                shard, path, sharding_count
+            )
+            .into());
+        }
+
+        Ok(Self {
+            buckets: shards.len() as u32,
+            sharding_count,
+            shards,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut content = format!("{}\n", self.sharding_count);
+        for shard in &self.shards {
+            content.push_str(&format!("{}\n", shard));
+        }
+
+        // Replace the file at once, a partial map would misroute rows
+        let tmp = path.with_extension("buckets.tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Loads the map from `path`, or assigns buckets round-robin and saves it if the file does
+    /// not exist. Errors if the stored map was made for another bucket or sharding count.
+    pub(crate) fn init(&mut self, path: &Path, sharding_count: u32) -> Result<()> {
+        if !path.exists() {
+            self.assign(sharding_count)?;
+            log::debug!("Create bucket map: {:?}", path);
+            return self.save(path);
+        }
+
+        let map = Self::load(path)?;
+        if map.buckets != self.buckets {
+            return Err(format!(
+                "Bucket map {:?} has {} buckets, expect {}",
+                path, map.buckets, self.buckets
+            )
+            .into());
+        }
+        if map.sharding_count != sharding_count {
+            return Err(format!(
+                "Bucket map {:?} is made for {} shards, expect {}, rebalance it first",
+                path, map.sharding_count, sharding_count
+            )
+            .into());
+        }
+        *self = map;
+
+        Ok(())
+    }
+
+    /// Assigns buckets round-robin.
+    pub(crate) fn assign(&mut self, sharding_count: u32) -> Result<()> {
+        check_sharding_count(sharding_count)?;
+        self.sharding_count = sharding_count;
+        self.shards = (0..self.buckets).map(|b| b % sharding_count).collect();
+
+        Ok(())
+    }
+
+    pub fn sharding_count(&self) -> u32 {
+        self.sharding_count
+    }
+
+    pub fn buckets(&self) -> u32 {
+        self.buckets
+    }
+
+    pub fn bucket(&self, params: &[SqlParam]) -> u32 {
+        crc32(&key_bytes(params)) % self.buckets
+    }
+
+    pub fn shard(&self, bucket: u32) -> Option<u32> {
+        self.shards.get(bucket as usize).cloned()
+    }
+
+    /// Reassigns buckets to `sharding_count` shards with as few moves as possible, every shard
+    /// ends up with the same number of buckets give or take one. Returns the moves as
+    /// `(bucket, from, to)`. Errors if `sharding_count` is 0.
+    pub fn rebalance(&mut self, sharding_count: u32) -> Result<Vec<(u32, u32, u32)>> {
+        check_sharding_count(sharding_count)?;
+        let count = sharding_count as usize;
+        let quota = |shard: usize| {
+            self.buckets as usize / count + (shard < self.buckets as usize % count) as usize
+        };
+        let quotas: Vec<usize> = (0..count).map(quota).collect();
+
+        // Keep as many buckets in place as the quotas allow
+        let mut kept = vec![0usize; count];
+        let mut moving = vec![];
+        for (bucket, shard) in self.shards.iter().enumerate() {
+            let shard = *shard as usize;
+            if shard < count && kept[shard] < quotas[shard] {
+                kept[shard] += 1;
+            } else {
+                moving.push(bucket);
+            }
+        }
+
+        let mut moves = vec![];
+        let mut target = 0;
+        for bucket in moving {
+            while kept[target] >= quotas[target] {
+                target += 1;
+            }
+            kept[target] += 1;
+            moves.push((bucket as u32, self.shards[bucket], target as u32));
+            self.shards[bucket] = target as u32;
+        }
+        self.sharding_count = sharding_count;
+
+        Ok(moves)
+    }
+}
+
+fn check_sharding_count(sharding_count: u32) -> Result<()> {
+    if sharding_count == 0 {
+        return Err("Bucket map needs at least one shard".into());
+    }
+
+    Ok(())
+}
+
+fn as_i64(param: &SqlParam) -> Option<i64> {
+    match param {
+        SqlParam::I64(n) => Some(*n),
+        SqlParam::U32(n) => Some(*n as i64),
+        SqlParam::U16(n) => Some(*n as i64),
+        // Integral reals are stored as integers in INTEGER columns, route them alike
+        SqlParam::F64(f) if f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64 => {
+            Some(*f as i64)
+        }
+        SqlParam::F64(_) | SqlParam::String(_) | SqlParam::Blob(_) | SqlParam::Null => None,
+    }
+}
+
+/// Integers of every width are hashed as their decimal text, like Lookup keys, so `U32(1)`,
+/// `I64(1)` and `String("1")` land on the same shard as SQLite stores them alike in an INTEGER
+/// column.
+fn value_bytes(param: &SqlParam) -> Vec<u8> {
+    match param {
+        SqlParam::String(s) => s.as_bytes().to_vec(),
+        SqlParam::Blob(b) => b.to_vec(),
+        SqlParam::Null => vec![],
+        p => match as_i64(p) {
+            Some(n) => n.to_string().into_bytes(),
+            None => match p {
+                SqlParam::F64(f) => f.to_bits().to_le_bytes().to_vec(),
+                _ => vec![],
+            },
+        },
+    }
+}
+
+/// A single value is hashed as is, values of a composite key are length-prefixed so
+/// `("a", "bc")` and `("ab", "c")` differ.
+fn key_bytes(params: &[SqlParam]) -> Vec<u8> {
+    match params {
+        [param] => value_bytes(param),
+        params => {
+            let mut bytes = vec![];
+            for param in params {
+                let value = value_bytes(param);
+                bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&value);
+            }
+            bytes
+        }
+    }
+}
+
+/// CRC-32 (IEEE), the result must never change or rows end up on the wrong shard.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod crud {
-    use shardingite::{ShardingIte, ShardingIteConfig, SqlParam, NO_PARAMS};
+    use shardingite::{
+        KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule, ShardingStrategy, SqlParam,
+        NO_PARAMS,
+    };
 
     #[test]
     fn test1() {
@@ -80,7 +83,7 @@ mod crud {
                 .unwrap()
                 .map(|v| v.unwrap())
                 .collect();
-            assert_eq!(rows, vec![(2 as u32, "name2".to_string(), 2 as u8)]);
+            assert_eq!(rows, vec![(2_u32, "name2".to_string(), 2_u8)]);
         }
 
         // Query range asc
@@ -98,7 +101,7 @@ mod crud {
             assert_eq!(
                 rows,
                 vec![
-                    (6 as u32, "name6".to_string(), 6 as u8),
+                    (6_u32, "name6".to_string(), 6_u8),
                     (7, "name7".to_string(), 7),
                     (8, "name8".to_string(), 8),
                     (9, "name9".to_string(), 9),
@@ -124,7 +127,7 @@ mod crud {
                     (9, "name9".to_string(), 9),
                     (8, "name8".to_string(), 8),
                     (7, "name7".to_string(), 7),
-                    (6 as u32, "name6".to_string(), 6 as u8),
+                    (6_u32, "name6".to_string(), 6_u8),
                 ]
             );
         }
@@ -144,7 +147,7 @@ mod crud {
             assert_eq!(
                 rows,
                 vec![
-                    (2 as u32, "name2".to_string(), 2 as u8),
+                    (2_u32, "name2".to_string(), 2_u8),
                     (3, "name3".to_string(), 3),
                     (4, "name4".to_string(), 4),
                 ]
@@ -168,7 +171,7 @@ mod crud {
             assert_eq!(
                 rows,
                 vec![
-                    (3 as u32, "name3".to_string(), 3 as u8),
+                    (3_u32, "name3".to_string(), 3_u8),
                     (4, "name4".to_string(), 4),
                     (5, "name5".to_string(), 5),
                 ]
@@ -189,14 +192,14 @@ mod crud {
 
         let conn = ShardingIte::new(ShardingIteConfig {
             sharding_count: 2,
-            sharding_table: "user".to_string(),
-            sharding_column: "id".to_string(),
             sharding_path: Box::new(|index| format!("/tmp/shardingite_test/{}.sqlite", index)),
-            sharding_index: Box::new(|param| match param {
-                SqlParam::I64(n) => Ok(*n as u32 % 2),
-                SqlParam::U32(n) => Ok(n % 2),
-                p @ _ => Err(format!("Invalid param: {:?}", p).into()),
-            }),
+            sharding_rules: vec![ShardingRule {
+                table: "user".to_string(),
+                columns: vec!["id".to_string()],
+                strategy: ShardingStrategy::Modulo,
+            }],
+            reference_tables: vec![],
+            key_update: KeyUpdate::Reject,
         })
         .unwrap();
 
@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod exception {
-    use shardingite::{ShardingIte, ShardingIteConfig, SqlParam, NO_PARAMS};
+    use shardingite::{
+        KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule, ShardingStrategy, SqlParam,
+        NO_PARAMS,
+    };
 
     #[test]
     fn test1() {
@@ -36,6 +39,14 @@ mod exception {
             query(conn);
         }
 
+        // update sharding key
+        {
+            assert!(conn
+                .execute("UPDATE test SET value = 1 WHERE value = 0", NO_PARAMS)
+                .is_err());
+            query(conn);
+        }
+
         teardown(env);
     }
 
@@ -59,14 +70,14 @@ mod exception {
 
         let conn = ShardingIte::new(ShardingIteConfig {
             sharding_count: 2,
-            sharding_table: "test".to_string(),
-            sharding_column: "value".to_string(),
             sharding_path: Box::new(|index| format!("/tmp/shardingite_test/{}.sqlite", index)),
-            sharding_index: Box::new(|param| match param {
-                SqlParam::I64(n) => Ok(*n as u32 % 2),
-                SqlParam::U32(n) => Ok(n % 2),
-                p @ _ => Err(format!("Invalid param: {:?}", p).into()),
-            }),
+            sharding_rules: vec![ShardingRule {
+                table: "test".to_string(),
+                columns: vec!["value".to_string()],
+                strategy: ShardingStrategy::Modulo,
+            }],
+            reference_tables: vec![],
+            key_update: KeyUpdate::Reject,
         })
         .unwrap();
 
@@ -0,0 +1,408 @@
+#[cfg(test)]
+mod query {
+    use shardingite::{
+        KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule, ShardingStrategy, SqlParam,
+        NO_PARAMS,
+    };
+
+    const PATH: &str = "/tmp/shardingite_test_query";
+
+    #[test]
+    fn test1() {
+        let mut env = setup();
+        let conn = &mut env.conn;
+
+        // Write test data
+        {
+            let rows = vec![
+                (0, "carol", 1, SqlParam::F64(2.5)),
+                (1, "alice", 2, SqlParam::Null),
+                (2, "bob", 1, SqlParam::I64(10)),
+                (3, "dave", 2, SqlParam::String("n/a".to_string())),
+                (4, "eve", 1, SqlParam::Blob(vec![0])),
+                (5, "Bob", 2, SqlParam::I64(3)),
+            ];
+            let tx = conn.transaction().unwrap();
+            let mut stmt = tx
+                .prepare("INSERT INTO player (id, name, team, score) VALUES (?1, ?2, ?3, ?4)")
+                .unwrap();
+            for (id, name, team, score) in rows {
+                stmt.execute(vec![
+                    SqlParam::I64(id),
+                    SqlParam::String(name.to_string()),
+                    SqlParam::I64(team),
+                    score,
+                ])
+                .unwrap();
+            }
+            drop(stmt);
+            tx.commit().unwrap();
+        }
+
+        let ids = |sql: &str| -> Vec<i64> {
+            conn.prepare(sql)
+                .unwrap()
+                .query_map(NO_PARAMS, |row| row.get(0))
+                .unwrap()
+                .map(|v| v.unwrap())
+                .collect()
+        };
+
+        // Order by mixed types
+        {
+            assert_eq!(
+                ids("SELECT id, score FROM player ORDER BY score"),
+                vec![1, 0, 5, 2, 3, 4]
+            );
+            assert_eq!(
+                ids("SELECT id, score FROM player ORDER BY score DESC"),
+                vec![4, 3, 2, 5, 0, 1]
+            );
+        }
+
+        // Order by text
+        {
+            assert_eq!(
+                ids("SELECT id, name FROM player ORDER BY name"),
+                vec![5, 1, 2, 0, 3, 4]
+            );
+            assert_eq!(
+                ids("SELECT id, name FROM player ORDER BY name DESC LIMIT 2"),
+                vec![4, 3]
+            );
+        }
+
+        // Order by several columns
+        {
+            assert_eq!(
+                ids("SELECT id, name, team FROM player ORDER BY team, name DESC"),
+                vec![4, 0, 2, 3, 1, 5]
+            );
+            assert_eq!(
+                ids("SELECT id, team FROM player ORDER BY team DESC, id ASC LIMIT 4"),
+                vec![1, 3, 5, 0]
+            );
+        }
+
+        // Order by ordinal, alias, expression and columns not selected
+        {
+            assert_eq!(
+                ids("SELECT id, team FROM player ORDER BY 2, 1 DESC"),
+                vec![4, 2, 0, 5, 3, 1]
+            );
+            assert_eq!(
+                ids("SELECT id, name AS n FROM player ORDER BY N"),
+                vec![5, 1, 2, 0, 3, 4]
+            );
+            assert_eq!(
+                ids("SELECT id, lower(name) FROM player ORDER BY lower(name), id"),
+                vec![1, 2, 5, 0, 3, 4]
+            );
+            assert_eq!(
+                ids("SELECT id FROM player ORDER BY lower(name) DESC, id DESC"),
+                vec![4, 3, 0, 5, 2, 1]
+            );
+            assert_eq!(
+                ids("SELECT * FROM player ORDER BY team DESC, name LIMIT 2"),
+                vec![5, 1]
+            );
+
+            let mut stmt = conn
+                .prepare("SELECT name FROM player WHERE team = 1 ORDER BY id DESC")
+                .unwrap();
+            let mut rows = stmt.query(NO_PARAMS).unwrap();
+            let row = rows.next().unwrap().unwrap();
+            assert_eq!(row.get::<String>(0).unwrap(), "eve");
+            assert!(row.get::<i64>(1).is_err());
+            drop(rows);
+            drop(stmt);
+
+            let plan = conn
+                .explain("SELECT name FROM player ORDER BY id", vec![])
+                .unwrap();
+            assert_eq!(plan.shards[0].1, "SELECT name, id FROM player ORDER BY id");
+
+            assert!(conn.prepare("SELECT id FROM player ORDER BY 2").is_err());
+        }
+
+        // Order by with nulls order and collations
+        {
+            assert_eq!(
+                ids("SELECT id, score FROM player ORDER BY score NULLS LAST"),
+                vec![0, 5, 2, 3, 4, 1]
+            );
+            assert_eq!(
+                ids("SELECT id, score FROM player ORDER BY score DESC NULLS FIRST"),
+                vec![1, 4, 3, 2, 5, 0]
+            );
+            assert_eq!(
+                ids("SELECT id FROM player ORDER BY name COLLATE NOCASE, id"),
+                vec![1, 2, 5, 0, 3, 4]
+            );
+            assert_eq!(
+                ids("SELECT id, name COLLATE nocase AS n FROM player ORDER BY n DESC, id"),
+                vec![4, 3, 0, 2, 5, 1]
+            );
+
+            conn.execute(
+                "INSERT INTO player (id, name, team) VALUES (6, 'bob  ', 3)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            assert_eq!(
+                ids("SELECT id FROM player WHERE name LIKE 'b%' ORDER BY name COLLATE RTRIM, id DESC"),
+                vec![5, 6, 2]
+            );
+
+            assert_eq!(
+                ids("SELECT id FROM player WHERE team = 1 ORDER BY name COLLATE REVERSE"),
+                vec![4, 0, 2]
+            );
+        }
+
+        // Aggregate functions
+        {
+            let (sum, min, max, count): (i64, i64, i64, i64) = conn
+                .query_row(
+                    "SELECT sum(team), min(id), max(id), count(*) FROM player",
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .unwrap();
+            assert_eq!((sum, min, max, count), (12, 0, 6, 7));
+
+            let (min, max): (f64, Vec<u8>) = conn
+                .query_row(
+                    "SELECT min(score), max(score) AS m FROM player",
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!((min, max), (2.5, vec![0]));
+
+            let (sum, min, count): (Option<i64>, Option<String>, i64) = conn
+                .query_row(
+                    "SELECT sum(team), min(name), count(*) FROM player WHERE team = 9",
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap();
+            assert_eq!((sum, min, count), (None, None, 0));
+            assert_eq!(ids("SELECT count(*) FROM player WHERE team = 9"), vec![0]);
+            assert!(ids("SELECT count(*) FROM player LIMIT 1 OFFSET 1").is_empty());
+
+            conn.execute(
+                "INSERT INTO player (id, name, team) VALUES (7, 'max', ?1), (8, 'one', 1)",
+                vec![SqlParam::I64(i64::MAX)],
+            )
+            .unwrap();
+            assert_eq!(
+                ids("SELECT max(team) FROM player WHERE id >= 7"),
+                vec![i64::MAX]
+            );
+            let fails = |sql: &str| {
+                conn.query_row::<i64, _>(sql, NO_PARAMS, |row| row.get(0))
+                    .is_err()
+            };
+            assert!(fails("SELECT sum(team) FROM player WHERE id >= 7"));
+            assert!(fails("SELECT team, count(*) FROM player"));
+            assert!(fails("SELECT count(*) + 1 FROM player"));
+            assert!(fails("SELECT count(*) FROM player GROUP BY team"));
+        }
+
+        // Average, total and group_concat
+        {
+            let real = |sql: &str| -> Option<f64> {
+                conn.query_row(sql, NO_PARAMS, |row| row.get(0)).unwrap()
+            };
+            assert_eq!(
+                real("SELECT avg(team) FROM player WHERE id < 7"),
+                Some(12.0 / 7.0)
+            );
+            assert_eq!(
+                real("SELECT avg(score) AS a FROM player WHERE id IN (0, 1, 5)"),
+                Some(2.75)
+            );
+            assert_eq!(
+                real("SELECT avg(team) FROM player WHERE id >= 7"),
+                Some((i64::MAX as f64 + 1.0) / 2.0)
+            );
+            assert_eq!(real("SELECT avg(team) FROM player WHERE team = 9"), None);
+            assert_eq!(
+                real("SELECT total(team) FROM player WHERE id < 7"),
+                Some(12.0)
+            );
+            assert_eq!(
+                real("SELECT total(team) FROM player WHERE team = 9"),
+                Some(0.0)
+            );
+
+            let (avg, count): (f64, i64) = conn
+                .query_row(
+                    "SELECT avg(id), count(*) FROM player WHERE team = 1",
+                    NO_PARAMS,
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!((avg, count), (3.5, 4));
+
+            let names = |sql: &str, separator: &str| -> Vec<String> {
+                let names: String = conn.query_row(sql, NO_PARAMS, |row| row.get(0)).unwrap();
+                let mut names: Vec<String> =
+                    names.split(separator).map(|n| n.to_string()).collect();
+                names.sort();
+                names
+            };
+            assert_eq!(
+                names(
+                    "SELECT group_concat(name, '; ') FROM player WHERE team = 1",
+                    "; "
+                ),
+                vec!["bob", "carol", "eve", "one"]
+            );
+            assert_eq!(
+                names("SELECT group_concat(name) FROM player WHERE team = 2", ","),
+                vec!["Bob", "alice", "dave"]
+            );
+
+            let plan = conn
+                .explain("SELECT avg(team) AS a FROM player", vec![])
+                .unwrap();
+            assert_eq!(
+                plan.shards[0].1,
+                "SELECT total(team), count(team) FROM player"
+            );
+        }
+
+        // Count of a column and of distinct values
+        {
+            let counts = |sql: &str| -> (i64, i64) {
+                conn.query_row(sql, NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))
+                    .unwrap()
+            };
+            assert_eq!(counts("SELECT count(score), count(*) FROM player"), (5, 9));
+            assert_eq!(
+                counts("SELECT count(DISTINCT id), count(*) FROM player"),
+                (9, 9)
+            );
+
+            assert_eq!(ids("SELECT count(DISTINCT team) FROM player"), vec![4]);
+            assert_eq!(
+                ids("SELECT count(DISTINCT score) AS n FROM player"),
+                vec![5]
+            );
+            assert_eq!(
+                ids("SELECT count(DISTINCT team) FROM player WHERE team = 9"),
+                vec![0]
+            );
+            assert_eq!(
+                ids("SELECT count(DISTINCT p.id) FROM player AS p WHERE team = 1"),
+                vec![4]
+            );
+
+            let plan = conn
+                .explain("SELECT count(DISTINCT team) FROM player", vec![])
+                .unwrap();
+            assert_eq!(plan.shards[0].1, "SELECT DISTINCT team FROM player");
+            let plan = conn
+                .explain("SELECT count(DISTINCT id), count(*) FROM player", vec![])
+                .unwrap();
+            assert_eq!(
+                plan.shards[0].1,
+                "SELECT count(DISTINCT id), count(*) FROM player"
+            );
+
+            let fails = |sql: &str| {
+                conn.query_row::<i64, _>(sql, NO_PARAMS, |row| row.get(0))
+                    .is_err()
+            };
+            assert!(fails("SELECT count(DISTINCT team), count(*) FROM player"));
+            assert!(fails("SELECT sum(DISTINCT team) FROM player"));
+
+            // Window functions are computed by each shard, not merged as aggregates
+            assert_eq!(
+                ids("SELECT id, count(*) OVER () FROM player ORDER BY id"),
+                (0..9).collect::<Vec<i64>>()
+            );
+            assert_eq!(
+                ids("SELECT id, avg(team) OVER () AS a FROM player WHERE id = 4"),
+                vec![4]
+            );
+        }
+
+        // Order by the collation declared on the column
+        {
+            conn.execute(
+                "INSERT INTO tag (id, name) VALUES (0, 'A'), (1, 'B'), (2, 'C'), (3, 'a'), (4, 'b'), (5, 'c')",
+                NO_PARAMS,
+            )
+            .unwrap();
+            assert_eq!(
+                ids("SELECT id FROM tag ORDER BY name, id"),
+                vec![0, 3, 1, 4, 2, 5]
+            );
+            assert_eq!(
+                ids("SELECT t.id, t.name AS n FROM tag AS t ORDER BY n DESC, id"),
+                vec![2, 5, 1, 4, 0, 3]
+            );
+            assert_eq!(
+                ids("SELECT id FROM tag ORDER BY name COLLATE BINARY"),
+                vec![0, 1, 2, 3, 4, 5]
+            );
+        }
+
+        teardown(env);
+    }
+
+    struct Env {
+        pub conn: ShardingIte,
+    }
+
+    fn setup() -> Env {
+        env_logger::init();
+
+        std::fs::remove_dir_all(PATH).ok();
+
+        let mut conn = ShardingIte::new(ShardingIteConfig {
+            sharding_count: 3,
+            sharding_path: Box::new(|index| format!("{}/{}.sqlite", PATH, index)),
+            sharding_rules: vec![
+                ShardingRule {
+                    table: "player".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+                ShardingRule {
+                    table: "tag".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+            ],
+            reference_tables: vec![],
+            key_update: KeyUpdate::Reject,
+        })
+        .unwrap();
+
+        conn.execute_batch(
+            r#"
+        CREATE TABLE IF NOT EXISTS player (
+            id INTEGER NOT NULL,
+            name VARCHAR NOT NULL,
+            team INTEGER NOT NULL,
+            score BLOB
+        );
+        CREATE TABLE IF NOT EXISTS tag (
+            id INTEGER NOT NULL,
+            name TEXT COLLATE NOCASE NOT NULL
+        );
+        "#,
+        )
+        .unwrap();
+
+        conn.create_collation("reverse", |a, b| b.cmp(a)).unwrap();
+
+        Env { conn }
+    }
+
+    fn teardown(_env: Env) {}
+}
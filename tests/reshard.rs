@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod reshard {
+    use shardingite::{
+        rusqlite, BucketMap, KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule,
+        ShardingStrategy, SqlParam, NO_PARAMS,
+    };
+
+    const PATH: &str = "/tmp/shardingite_test_reshard";
+
+    #[test]
+    fn test1() {
+        let Env { conn } = setup();
+
+        // Write test data
+        {
+            let tx = conn.transaction().unwrap();
+            let mut stmt = tx
+                .prepare("INSERT INTO user (id, name) VALUES (?1, ?2)")
+                .unwrap();
+            for i in 0..30 {
+                stmt.execute(vec![
+                    SqlParam::I64(i),
+                    SqlParam::String(format!("name{}", i)),
+                ])
+                .unwrap();
+            }
+            drop(stmt);
+            let mut stmt = tx.prepare("INSERT INTO item (id) VALUES (?1)").unwrap();
+            for i in 0..30 {
+                stmt.execute(vec![SqlParam::I64(i)]).unwrap();
+            }
+            drop(stmt);
+            let mut stmt = tx
+                .prepare("INSERT INTO ledger (id, part, note) VALUES (?1, ?2, 'note')")
+                .unwrap();
+            for i in 0..30 {
+                for part in &["a", "b"] {
+                    stmt.execute(vec![SqlParam::I64(i), SqlParam::String(part.to_string())])
+                        .unwrap();
+                }
+            }
+            drop(stmt);
+            tx.commit().unwrap();
+            conn.execute("INSERT INTO city (name) VALUES ('paris')", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Grow from 2 to 3 shards
+        {
+            let report = conn.reshard(&mut config(3)).unwrap();
+            assert_eq!(
+                report.table_rows,
+                vec![
+                    ("user".to_string(), 30),
+                    ("item".to_string(), 30),
+                    ("ledger".to_string(), 60)
+                ]
+            );
+            assert!(report.moved_rows > 0);
+
+            for i in 0..3 {
+                let ids: Vec<i64> = shard(i)
+                    .prepare("SELECT id FROM user")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect();
+                assert_eq!(ids.len(), 10);
+                assert!(ids.iter().all(|id| id % 3 == i as i64));
+
+                // WITHOUT ROWID tables are paged by their primary key
+                let ids: Vec<i64> = shard(i)
+                    .prepare("SELECT id FROM ledger")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect();
+                assert_eq!(ids.len(), 20);
+                assert!(ids.iter().all(|id| id % 3 == i as i64));
+
+                let count: u32 = shard(i)
+                    .query_row("SELECT count(*) FROM city", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1);
+            }
+
+            let map = BucketMap::load(&BucketMap::path_of(&format!("{}/0.sqlite", PATH), "item"))
+                .unwrap();
+            assert_eq!(map.sharding_count(), 3);
+            for i in 0..30 {
+                let index = map.shard(map.bucket(&[SqlParam::I64(i)])).unwrap();
+                let count: u32 = shard(index)
+                    .query_row("SELECT count(*) FROM item WHERE id = ?1", [i], |row| {
+                        row.get(0)
+                    })
+                    .unwrap();
+                assert_eq!(count, 1);
+            }
+        }
+
+        // A batch interrupted after copying its rows to the target is completed
+        {
+            let rowid: i64 = shard(2)
+                .query_row("SELECT rowid FROM user WHERE id = 20", [], |row| row.get(0))
+                .unwrap();
+            shard(1)
+                .execute_batch(&format!(
+                    "CREATE TABLE _shardingite_reshard (source TEXT NOT NULL, tbl TEXT NOT NULL, id TEXT NOT NULL);
+                    INSERT INTO _shardingite_reshard VALUES ('{}/2.sqlite', 'user', '{}');
+                    INSERT INTO user (id, name) VALUES (20, 'name20');",
+                    PATH, rowid
+                ))
+                .unwrap();
+
+            let conn = ShardingIte::new(config(3)).unwrap();
+            let report = conn.reshard(&mut config(3)).unwrap();
+            assert_eq!(report.moved_rows, 1);
+            assert_eq!(report.table_rows[0], ("user".to_string(), 30));
+            let count: u32 = shard(2)
+                .query_row("SELECT count(*) FROM user WHERE id = 20", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+
+        // Running again moves nothing
+        {
+            let conn = ShardingIte::new(config(3)).unwrap();
+            let report = conn.reshard(&mut config(3)).unwrap();
+            assert_eq!(report.moved_rows, 0);
+
+            let conn = ShardingIte::new(config(3)).unwrap();
+            let name: String = conn
+                .query_row(
+                    "SELECT name FROM user WHERE id = ?1",
+                    vec![SqlParam::I64(20)],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(name, "name20");
+            teardown(Env { conn });
+        }
+    }
+
+    fn shard(index: u32) -> rusqlite::Connection {
+        rusqlite::Connection::open(format!("{}/{}.sqlite", PATH, index)).unwrap()
+    }
+
+    fn config(count: u32) -> ShardingIteConfig {
+        ShardingIteConfig {
+            sharding_count: count,
+            sharding_path: Box::new(|index| format!("{}/{}.sqlite", PATH, index)),
+            sharding_rules: vec![
+                ShardingRule {
+                    table: "user".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+                ShardingRule {
+                    table: "item".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Bucket(BucketMap::new(16).unwrap()),
+                },
+                ShardingRule {
+                    table: "ledger".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+            ],
+            reference_tables: vec!["city".to_string()],
+            key_update: KeyUpdate::Reject,
+        }
+    }
+
+    struct Env {
+        pub conn: ShardingIte,
+    }
+
+    fn setup() -> Env {
+        env_logger::init();
+
+        std::fs::remove_dir_all(PATH).ok();
+
+        let conn = ShardingIte::new(config(2)).unwrap();
+
+        conn.execute_batch(
+            r#"
+        CREATE TABLE IF NOT EXISTS user (
+            id INTEGER NOT NULL,
+            name VARCHAR NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS index1 ON user (
+            id
+        );
+
+        CREATE TABLE IF NOT EXISTS item (
+            id INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS ledger (
+            id INTEGER NOT NULL,
+            part VARCHAR NOT NULL,
+            note VARCHAR NOT NULL,
+            PRIMARY KEY (id, part)
+        ) WITHOUT ROWID;
+
+        CREATE TABLE IF NOT EXISTS city (
+            name VARCHAR NOT NULL UNIQUE
+        );
+        "#,
+        )
+        .unwrap();
+
+        Env { conn }
+    }
+
+    fn teardown(_env: Env) {}
+}
@@ -0,0 +1,1108 @@
+#[cfg(test)]
+mod router {
+    use shardingite::{
+        rusqlite, BucketMap, KeyUpdate, ShardingIte, ShardingIteConfig, ShardingRule,
+        ShardingStrategy, SqlParam, NO_PARAMS,
+    };
+
+    const PATH: &str = "/tmp/shardingite_test_router";
+
+    #[test]
+    fn test1() {
+        let mut env = setup();
+        let conn = &mut env.conn;
+
+        // Write test data
+        {
+            let tx = conn.transaction().unwrap();
+            let mut stmt = tx
+                .prepare("INSERT INTO user (id, name, age) VALUES (?1, ?2, ?3)")
+                .unwrap();
+            for i in 0..10 {
+                stmt.execute(vec![
+                    SqlParam::U32(i),
+                    SqlParam::String(format!("name{}", i)),
+                    SqlParam::U16(i as u16),
+                ])
+                .unwrap();
+            }
+            drop(stmt);
+            tx.commit().unwrap();
+        }
+
+        // Plant a row into a shard it does not belong to, only full match can see it
+        shard(1)
+            .execute(
+                "INSERT INTO user (id, name, age) VALUES (2, 'stray', 2)",
+                [],
+            )
+            .unwrap();
+
+        // Select by equality
+        {
+            let count: u32 = conn
+                .query_row("SELECT count(*) FROM user WHERE id = 2", NO_PARAMS, |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+
+            let count: u32 = conn
+                .query_row(
+                    "SELECT count(*) FROM user WHERE age = 2",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 2);
+
+            // Keys the strategy can not route are read from every shard, with the planted row
+            let count = |sql: &str, params: Vec<SqlParam>| -> u32 {
+                conn.query_row(sql, params, |row| row.get(0)).unwrap()
+            };
+            assert_eq!(
+                count("SELECT count(*) FROM user WHERE id = NULL", NO_PARAMS),
+                0
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM user WHERE id = '2'", NO_PARAMS),
+                2
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM user WHERE id = 1.5", NO_PARAMS),
+                0
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM user WHERE id = ?1",
+                    vec![SqlParam::String("2".to_string())]
+                ),
+                2
+            );
+            conn.execute("DELETE FROM user WHERE id = NULL", NO_PARAMS)
+                .unwrap();
+            conn.execute("UPDATE user SET age = 0 WHERE id = 1.5", NO_PARAMS)
+                .unwrap();
+            assert!(conn
+                .execute(
+                    "INSERT INTO user (id, name, age) VALUES (NULL, 'x', 0)",
+                    NO_PARAMS
+                )
+                .is_err());
+        }
+
+        // Explicit shards
+        {
+            let name = |sql: &str, shards: Option<&[u32]>| -> String {
+                let mut stmt = match shards {
+                    Some(shards) => conn.prepare_on(shards, sql).unwrap(),
+                    None => conn.prepare(sql).unwrap(),
+                };
+                let mut rows = stmt.query(NO_PARAMS).unwrap();
+                rows.next().unwrap().unwrap().get(0).unwrap()
+            };
+
+            assert_eq!(
+                name("SELECT name FROM user WHERE id = 2", Some(&[1])),
+                "stray"
+            );
+            assert_eq!(
+                name("SELECT /*+ shard(1) */ name FROM user WHERE id = 2", None),
+                "stray"
+            );
+            assert_eq!(
+                name(
+                    "SELECT name FROM user WHERE id = 2 AND name != '/*+ shard(1) */'",
+                    None
+                ),
+                "name2"
+            );
+            assert!(conn.prepare_on(&[2], "SELECT name FROM user").is_err());
+            assert!(conn
+                .prepare("SELECT /*+ shard(a) */ name FROM user")
+                .is_err());
+        }
+
+        // Select by param in conjunction
+        {
+            let name: String = conn
+                .query_row(
+                    "SELECT name FROM user WHERE age >= 0 AND (id = ?1 AND age = ?2)",
+                    vec![SqlParam::I64(2), SqlParam::I64(2)],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(name, "name2");
+        }
+
+        // Select by in list and disjunction
+        {
+            let count = |sql: &str, params: Vec<SqlParam>| -> u32 {
+                conn.query_row(sql, params, |row| row.get(0)).unwrap()
+            };
+
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM user WHERE id IN (?1, ?2)",
+                    vec![SqlParam::I64(2), SqlParam::I64(4)]
+                ),
+                2
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM user WHERE id = 2 OR id = 4",
+                    NO_PARAMS
+                ),
+                2
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM user WHERE id = 2 OR id = 3",
+                    NO_PARAMS
+                ),
+                3
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM user WHERE id IN (2, 4) OR age = 3",
+                    NO_PARAMS
+                ),
+                4
+            );
+        }
+
+        // Split multi-row insert
+        {
+            conn.execute(
+                "INSERT INTO user (id, name, age) VALUES (?1, ?2, 10), (11, 'name11', ?3), (?4, 'name12', ?3 + 1)",
+                vec![
+                    SqlParam::I64(10),
+                    SqlParam::String("name10".to_string()),
+                    SqlParam::I64(11),
+                    SqlParam::I64(12),
+                ],
+            )
+            .unwrap();
+
+            let rows = |index: u32| -> Vec<(u32, String, u32)> {
+                shard(index)
+                    .prepare("SELECT id, name, age FROM user WHERE id >= 10 ORDER BY id")
+                    .unwrap()
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(
+                rows(0),
+                vec![
+                    (10, "name10".to_string(), 10),
+                    (12, "name12".to_string(), 12)
+                ]
+            );
+            assert_eq!(rows(1), vec![(11, "name11".to_string(), 11)]);
+
+            conn.execute("DELETE FROM user WHERE id >= 10", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Numbered, anonymous and named placeholders
+        {
+            conn.execute(
+                "INSERT INTO user (id, name, age) VALUES (?3, ?1, ?2)",
+                vec![
+                    SqlParam::String("name21".to_string()),
+                    SqlParam::I64(21),
+                    SqlParam::I64(21),
+                ],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO user (name, age, id) VALUES (?, ?, ?)",
+                vec![
+                    SqlParam::String("name23".to_string()),
+                    SqlParam::I64(23),
+                    SqlParam::I64(23),
+                ],
+            )
+            .unwrap();
+            {
+                let tx = conn.transaction().unwrap();
+                let mut stmt = tx
+                    .prepare("INSERT INTO user (id, name, age) VALUES (:id, @name, $age + :id)")
+                    .unwrap();
+                stmt.execute_named(vec![
+                    ("@name", SqlParam::String("name25".to_string())),
+                    (":id", SqlParam::I64(25)),
+                    ("$age", SqlParam::I64(0)),
+                ])
+                .unwrap();
+                assert!(stmt
+                    .execute_named(vec![(":id", SqlParam::I64(27))])
+                    .is_err());
+                drop(stmt);
+                tx.commit().unwrap();
+            }
+            assert!(conn
+                .prepare("SELECT * FROM user WHERE id = ?2000000000")
+                .is_err());
+            assert!(conn.prepare("SELECT * FROM user WHERE id = ?32766").is_ok());
+
+            let count: u32 = shard(1)
+                .query_row(
+                    "SELECT count(*) FROM user WHERE id IN (21, 23, 25) AND id = age",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 3);
+
+            let mut stmt = conn
+                .prepare("SELECT name FROM user WHERE id = :id AND name != '?1 :id'")
+                .unwrap();
+            let mut rows = stmt.query_named(vec![(":id", SqlParam::I64(25))]).unwrap();
+            let name: String = rows.next().unwrap().unwrap().get(0).unwrap();
+            assert_eq!(name, "name25");
+            drop(rows);
+            drop(stmt);
+
+            conn.execute("DELETE FROM user WHERE id >= 20", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Rule of another table
+        {
+            conn.execute("INSERT INTO event (id, user_id) VALUES (2, 2)", NO_PARAMS)
+                .unwrap();
+            shard(0)
+                .execute("INSERT INTO event (id, user_id) VALUES (3, 2)", [])
+                .unwrap();
+
+            let count: u32 = shard(1)
+                .query_row("SELECT count(*) FROM event WHERE id = 2", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+
+            let id: u32 = conn
+                .query_row(
+                    "SELECT id FROM event WHERE user_id = ?1",
+                    vec![SqlParam::I64(2)],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(id, 2);
+        }
+
+        // Hash of string key
+        {
+            let token = |s: &str| SqlParam::String(s.to_string());
+            let index = ShardingStrategy::Hash.index(&[token("abc")], 2).unwrap();
+            conn.execute(
+                "INSERT INTO session (token, user_id) VALUES (?1, 1)",
+                vec![token("abc")],
+            )
+            .unwrap();
+            shard(1 - index)
+                .execute("INSERT INTO session (token, user_id) VALUES ('abc', 2)", [])
+                .unwrap();
+
+            let count: u32 = shard(index)
+                .query_row("SELECT count(*) FROM session", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 1);
+
+            let user_id: u32 = conn
+                .query_row(
+                    "SELECT user_id FROM session WHERE token = ?1",
+                    vec![token("abc")],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(user_id, 1);
+
+            assert_eq!(
+                ShardingStrategy::Hash
+                    .index(&[SqlParam::U32(7)], 2)
+                    .unwrap(),
+                ShardingStrategy::Hash
+                    .index(&[SqlParam::I64(7)], 2)
+                    .unwrap()
+            );
+            assert_eq!(
+                ShardingStrategy::Modulo
+                    .index(&[SqlParam::I64(-1)], 2)
+                    .unwrap(),
+                1
+            );
+            assert!(ShardingStrategy::Modulo.index(&[token("abc")], 2).is_err());
+
+            // Integer keys hash like their text, as SQLite stores '2' as 2 in an INTEGER column
+            for i in 0..8 {
+                assert_eq!(
+                    ShardingStrategy::Hash
+                        .index(&[SqlParam::I64(i)], 2)
+                        .unwrap(),
+                    ShardingStrategy::Hash
+                        .index(&[token(&i.to_string())], 2)
+                        .unwrap()
+                );
+            }
+            conn.execute("INSERT INTO visit (id) VALUES ('2'), (3)", NO_PARAMS)
+                .unwrap();
+            let visits =
+                |sql: &str| -> u32 { conn.query_row(sql, NO_PARAMS, |row| row.get(0)).unwrap() };
+            assert_eq!(visits("SELECT count(*) FROM visit WHERE id = 2"), 1);
+            assert_eq!(visits("SELECT count(*) FROM visit WHERE id = '3'"), 1);
+            let range = ShardingStrategy::Range(vec![(0, 0), (100, 1)]);
+            assert_eq!(range.index(&[SqlParam::I64(99)], 2).unwrap(), 0);
+            assert_eq!(range.index(&[SqlParam::I64(100)], 2).unwrap(), 1);
+            assert!(range.index(&[SqlParam::I64(-1)], 2).is_err());
+            let lookup =
+                ShardingStrategy::Lookup(vec![("abc".to_string(), 1)].into_iter().collect());
+            assert_eq!(lookup.index(&[token("abc")], 2).unwrap(), 1);
+            assert!(lookup.index(&[token("abd")], 2).is_err());
+        }
+
+        // Virtual buckets
+        {
+            let path = BucketMap::path_of(&format!("{}/0.sqlite", PATH), "item");
+            let map = BucketMap::load(&path).unwrap();
+            assert_eq!((map.buckets(), map.sharding_count()), (16, 2));
+
+            for i in 0..8 {
+                conn.execute("INSERT INTO item (id) VALUES (?1)", vec![SqlParam::I64(i)])
+                    .unwrap();
+            }
+            for i in 0..8 {
+                let index = map.shard(map.bucket(&[SqlParam::I64(i)])).unwrap();
+                let count: u32 = shard(index)
+                    .query_row("SELECT count(*) FROM item WHERE id = ?1", [i], |row| {
+                        row.get(0)
+                    })
+                    .unwrap();
+                assert_eq!(count, 1);
+            }
+
+            // Growing to 4 shards only moves the buckets of the new shards
+            let mut map = map;
+            let moves = map.rebalance(4).unwrap();
+            assert_eq!(moves.len(), 8);
+            assert!(moves.iter().all(|(_, from, to)| *from < 2 && *to >= 2));
+            assert_eq!(map.rebalance(4).unwrap().len(), 0);
+            assert_eq!(map.rebalance(2).unwrap().len(), 8);
+
+            // Empty maps are rejected rather than dividing by zero
+            assert!(map.rebalance(0).is_err());
+            assert!(BucketMap::new(0).is_err());
+        }
+
+        // String, negative, blob and null literals
+        {
+            conn.execute(
+                "INSERT INTO session (token, user_id) VALUES ('alice', 3), (X'0102', 4), (NULL, 5)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO user (id, name, age) VALUES (-3, 'negative', 0)",
+                NO_PARAMS,
+            )
+            .unwrap();
+
+            let user_id = |index: u32, sql: &str| -> u32 {
+                shard(index).query_row(sql, [], |row| row.get(0)).unwrap()
+            };
+            let index = |param: SqlParam| ShardingStrategy::Hash.index(&[param], 2).unwrap();
+            assert_eq!(
+                user_id(
+                    index(SqlParam::String("alice".to_string())),
+                    "SELECT user_id FROM session WHERE token = 'alice'"
+                ),
+                3
+            );
+            assert_eq!(
+                user_id(
+                    index(SqlParam::Blob(vec![1, 2])),
+                    "SELECT user_id FROM session WHERE token = X'0102'"
+                ),
+                4
+            );
+            assert_eq!(
+                user_id(
+                    index(SqlParam::Null),
+                    "SELECT user_id FROM session WHERE token IS NULL"
+                ),
+                5
+            );
+            assert_eq!(user_id(1, "SELECT count(*) FROM user WHERE id = -3"), 1);
+
+            let user_id: u32 = conn
+                .query_row(
+                    "SELECT user_id FROM session WHERE token = 'alice'",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(user_id, 3);
+            let name: String = conn
+                .query_row("SELECT name FROM user WHERE id = -3", NO_PARAMS, |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(name, "negative");
+            conn.execute("DELETE FROM user WHERE id = -3", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Composite key
+        {
+            conn.execute(
+                "INSERT INTO message (tenant_id, user_id, body) VALUES (1, 1, 'a'), (1, 2, 'b')",
+                NO_PARAMS,
+            )
+            .unwrap();
+            shard(1)
+                .execute(
+                    "INSERT INTO message (tenant_id, user_id, body) VALUES (1, 1, 'stray')",
+                    [],
+                )
+                .unwrap();
+
+            let count = |sql: &str, params: Vec<SqlParam>| -> u32 {
+                conn.query_row(sql, params, |row| row.get(0)).unwrap()
+            };
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM message WHERE user_id = ?1 AND tenant_id = 1",
+                    vec![SqlParam::I64(1)]
+                ),
+                1
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM message WHERE tenant_id = 1 AND user_id IN (1, 3)",
+                    NO_PARAMS
+                ),
+                1
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM message WHERE tenant_id = 1",
+                    NO_PARAMS
+                ),
+                3
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM message WHERE tenant_id = 1 AND (user_id = 1 OR body = 'b')",
+                    NO_PARAMS
+                ),
+                3
+            );
+        }
+
+        // Lookup keys missing from the map
+        {
+            conn.execute("INSERT INTO region (code) VALUES ('eu'), ('us')", NO_PARAMS)
+                .unwrap();
+            let count: u32 = shard(1)
+                .query_row("SELECT count(*) FROM region WHERE code = 'us'", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+
+            let count: u32 = conn
+                .query_row(
+                    "SELECT count(*) FROM region WHERE code = 'zz'",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 0);
+            conn.execute("DELETE FROM region WHERE code = 'zz'", NO_PARAMS)
+                .unwrap();
+            assert!(conn
+                .execute("INSERT INTO region (code) VALUES ('zz')", NO_PARAMS)
+                .is_err());
+        }
+
+        // Range pruning
+        {
+            conn.execute("INSERT INTO metric (ts) VALUES (50), (150)", NO_PARAMS)
+                .unwrap();
+            shard(0)
+                .execute("INSERT INTO metric (ts) VALUES (150)", [])
+                .unwrap();
+            shard(1)
+                .execute("INSERT INTO metric (ts) VALUES (50)", [])
+                .unwrap();
+
+            let count = |sql: &str, params: Vec<SqlParam>| -> u32 {
+                conn.query_row(sql, params, |row| row.get(0)).unwrap()
+            };
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM metric WHERE ts BETWEEN ?1 AND ?2",
+                    vec![SqlParam::I64(40), SqlParam::I64(60)]
+                ),
+                1
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM metric WHERE ts < 100", NO_PARAMS),
+                1
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM metric WHERE 100 <= ts", NO_PARAMS),
+                1
+            );
+            assert_eq!(
+                count(
+                    "SELECT count(*) FROM metric WHERE ts > 99 AND ts <= 150",
+                    NO_PARAMS
+                ),
+                1
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM metric WHERE ts > 10", NO_PARAMS),
+                4
+            );
+            assert_eq!(
+                count("SELECT count(*) FROM metric WHERE ts = -5", NO_PARAMS),
+                0
+            );
+            conn.execute("DELETE FROM metric WHERE ts IN (-5, -6)", NO_PARAMS)
+                .unwrap();
+            let mut stmt = conn
+                .prepare("SELECT ts FROM metric WHERE ts < 10 AND ts > 20")
+                .unwrap();
+            assert!(stmt.query(NO_PARAMS).unwrap().next().unwrap().is_none());
+
+            // Unsorted ranges would misroute, they are rejected up front
+            let unsorted = ShardingIte::new(ShardingIteConfig {
+                sharding_count: 2,
+                sharding_path: Box::new(|index| format!("{}/unsorted/{}.sqlite", PATH, index)),
+                sharding_rules: vec![ShardingRule {
+                    table: "metric".to_string(),
+                    columns: vec!["ts".to_string()],
+                    strategy: ShardingStrategy::Range(vec![(100, 1), (0, 0)]),
+                }],
+                reference_tables: vec![],
+                key_update: KeyUpdate::Move,
+            });
+            assert!(unsorted.is_err());
+        }
+
+        // Reference table
+        {
+            conn.execute(
+                "INSERT INTO city (name) VALUES (?1)",
+                vec![SqlParam::String("paris".to_string())],
+            )
+            .unwrap();
+
+            let count: u32 = conn
+                .query_row("SELECT count(*) FROM city", NO_PARAMS, |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 1);
+
+            // Write fails on one shard only, the other one must not keep it
+            shard(1)
+                .execute("INSERT INTO city (name) VALUES ('rome')", [])
+                .unwrap();
+            let mut stmt = conn.prepare("INSERT INTO city (name) VALUES (?1)").unwrap();
+            assert!(stmt
+                .execute(vec![SqlParam::String("rome".to_string())])
+                .is_err());
+            drop(stmt);
+
+            for i in 0..2 {
+                let count: u32 = shard(i)
+                    .query_row("SELECT count(*) FROM city", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1 + i);
+            }
+        }
+
+        // Explain
+        {
+            let plan = conn
+                .explain(
+                    "SELECT id FROM user WHERE id = ?1 ORDER BY id LIMIT 2 OFFSET 1",
+                    vec![SqlParam::I64(2)],
+                )
+                .unwrap();
+            assert_eq!(
+                plan.shards,
+                vec![(
+                    0,
+                    "SELECT id FROM user WHERE id = ?1 ORDER BY id".to_string()
+                )]
+            );
+            assert!(!plan.hinted);
+            let query = plan.query.unwrap();
+            let limit = query.limit.unwrap();
+            assert_eq!((limit.limit, limit.offset), (2, 1));
+            assert_eq!(query.order_by[0].column_index, 0);
+
+            let plan = conn
+                .explain(
+                    "INSERT INTO user (id, name, age) VALUES (1, ?1, 1), (2, ?2, 2)",
+                    vec![
+                        SqlParam::String("a".to_string()),
+                        SqlParam::String("b".to_string()),
+                    ],
+                )
+                .unwrap();
+            assert_eq!(
+                plan.shards,
+                vec![
+                    (
+                        0,
+                        "INSERT INTO user (id, name, age) VALUES (2, ?1, 2)".to_string()
+                    ),
+                    (
+                        1,
+                        "INSERT INTO user (id, name, age) VALUES (1, ?1, 1)".to_string()
+                    ),
+                ]
+            );
+            assert!(plan.query.is_none());
+
+            let plan = conn
+                .explain("SELECT /*+ shard(1) */ count(*) FROM user", NO_PARAMS)
+                .unwrap();
+            assert_eq!(plan.shards.len(), 1);
+            assert!(plan.hinted);
+        }
+
+        // Qualified, quoted and case-insensitive names
+        {
+            conn.execute(
+                "INSERT INTO main.USER (ID, name, age) VALUES (40, 'name40', 40)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                r#"INSERT INTO "User" ("Id", name, age) VALUES (41, 'name41', 41)"#,
+                NO_PARAMS,
+            )
+            .unwrap();
+            for i in 0..2 {
+                let ids: Vec<i64> = shard(i)
+                    .prepare("SELECT id FROM user WHERE id >= 40")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect();
+                assert_eq!(ids, vec![40 + i as i64]);
+            }
+
+            let shards = |sql: &str| -> Vec<u32> {
+                conn.explain(sql, vec![])
+                    .unwrap()
+                    .shards
+                    .into_iter()
+                    .map(|(i, _)| i)
+                    .collect()
+            };
+            assert_eq!(
+                shards("SELECT name FROM user AS u WHERE u.id = 41"),
+                vec![1]
+            );
+            assert_eq!(
+                shards("SELECT name FROM main.user WHERE main.User.ID = 40"),
+                vec![0]
+            );
+            assert_eq!(
+                shards("SELECT name FROM user AS u WHERE x.id = 41"),
+                vec![0, 1]
+            );
+            assert_eq!(
+                shards("UPDATE USER SET age = 1 WHERE user.id = 41"),
+                vec![1]
+            );
+            assert_eq!(shards("SELECT name FROM City"), vec![0]);
+
+            conn.execute("DELETE FROM user WHERE id >= 40", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Insert select routes every row by its own key
+        {
+            conn.execute(
+                "INSERT INTO event (id, user_id) SELECT id + 100, id FROM user WHERE id >= ?1 AND id < 8",
+                vec![SqlParam::I64(4)],
+            )
+            .unwrap();
+
+            let user_ids = |index: u32| -> Vec<i64> {
+                shard(index)
+                    .prepare("SELECT user_id FROM event WHERE id >= 100 ORDER BY user_id")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(user_ids(0), vec![5, 7]);
+            assert_eq!(user_ids(1), vec![4, 6]);
+
+            assert!(conn
+                .execute(
+                    "INSERT INTO event (id, user_id) SELECT id, id FROM user LIMIT 1",
+                    NO_PARAMS
+                )
+                .is_err());
+            assert!(conn
+                .execute("INSERT INTO event SELECT id, id FROM user", NO_PARAMS)
+                .is_err());
+
+            // A table without rule holds the same rows on every shard, read it once
+            conn.execute("INSERT INTO staging (id) VALUES (200), (201)", NO_PARAMS)
+                .unwrap();
+            conn.execute(
+                "INSERT INTO event (id, user_id) SELECT id, id FROM staging",
+                NO_PARAMS,
+            )
+            .unwrap();
+            let ids = |index: u32| -> Vec<i64> {
+                shard(index)
+                    .prepare("SELECT id FROM event WHERE id >= 200")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(ids(0), vec![201]);
+            assert_eq!(ids(1), vec![200]);
+
+            let plan = conn
+                .explain(
+                    "INSERT INTO event (id, user_id) SELECT id, id FROM staging",
+                    vec![],
+                )
+                .unwrap();
+            assert_eq!(
+                plan.shards,
+                vec![(0, "SELECT id, id FROM staging".to_string())]
+            );
+            let plan = conn
+                .explain(
+                    "INSERT INTO event (id, user_id) SELECT id, id FROM user",
+                    vec![],
+                )
+                .unwrap();
+            assert_eq!(plan.shards.len(), 2);
+            assert_eq!(plan.shards[1].1, "SELECT id, id FROM user");
+
+            conn.execute("DELETE FROM event WHERE id >= 100", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Upsert and replace
+        {
+            conn.execute(
+                "INSERT INTO account (id, balance) VALUES (1, 10), (2, 20)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO account (id, balance) VALUES (?1, ?2), (?3, ?4)
+                ON CONFLICT (id) DO UPDATE SET balance = balance + excluded.balance + ?5",
+                vec![
+                    SqlParam::I64(1),
+                    SqlParam::I64(5),
+                    SqlParam::I64(4),
+                    SqlParam::I64(7),
+                    SqlParam::I64(100),
+                ],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT OR REPLACE INTO account (id, balance) VALUES (2, 0)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "REPLACE INTO account (id, balance) VALUES (3, 30)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO account (id, balance) VALUES (1, 0) ON CONFLICT DO NOTHING",
+                NO_PARAMS,
+            )
+            .unwrap();
+
+            let rows = |index: u32| -> Vec<(i64, i64)> {
+                shard(index)
+                    .prepare("SELECT id, balance FROM account ORDER BY id")
+                    .unwrap()
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(rows(0), vec![(2, 0), (4, 7)]);
+            assert_eq!(rows(1), vec![(1, 115), (3, 30)]);
+
+            assert!(conn
+                .execute(
+                    "INSERT INTO account (id, balance) VALUES (1, 0) ON CONFLICT (id) DO UPDATE SET id = 5",
+                    NO_PARAMS
+                )
+                .is_err());
+
+            let plan = conn
+                .explain(
+                    "INSERT INTO account (id, balance) VALUES (1, 0) ON CONFLICT (id) DO NOTHING",
+                    vec![],
+                )
+                .unwrap();
+            assert_eq!(plan.shards.len(), 1);
+            assert!(plan.statement.ends_with("ON CONFLICT (id) DO NOTHING"));
+        }
+
+        // Update by equality
+        {
+            conn.execute("UPDATE user SET age = 20 WHERE id = 2", NO_PARAMS)
+                .unwrap();
+            let age: u32 = shard(1)
+                .query_row("SELECT age FROM user WHERE id = 2", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(age, 2);
+        }
+
+        // Update of the sharding key moves the row
+        {
+            conn.execute(
+                "INSERT INTO user (id, name, age) VALUES (30, 'name30', 30)",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE user SET id = ?2, age = 31 WHERE id = ?1",
+                vec![SqlParam::I64(30), SqlParam::I64(31)],
+            )
+            .unwrap();
+
+            let rows = |index: u32| -> Vec<(u32, String, u32)> {
+                shard(index)
+                    .prepare("SELECT id, name, age FROM user WHERE id >= 30")
+                    .unwrap()
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(rows(0), vec![]);
+            assert_eq!(rows(1), vec![(31, "name30".to_string(), 31)]);
+
+            conn.execute("DELETE FROM user WHERE id >= 30", NO_PARAMS)
+                .unwrap();
+        }
+
+        // Update of a rowid alias or WITHOUT ROWID key moves the row
+        {
+            conn.execute("UPDATE account SET id = 5 WHERE id = 2", NO_PARAMS)
+                .unwrap();
+            let ids = |index: u32, table: &str| -> Vec<i64> {
+                shard(index)
+                    .prepare(&format!("SELECT id FROM {} ORDER BY id", table))
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .map(|v| v.unwrap())
+                    .collect()
+            };
+            assert_eq!(ids(0, "account"), vec![4]);
+            assert_eq!(ids(1, "account"), vec![1, 3, 5]);
+            let balance: i64 = conn
+                .query_row(
+                    "SELECT balance FROM account WHERE id = 5",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(balance, 0);
+
+            conn.execute(
+                "INSERT INTO ledger (id, note) VALUES (2, 'a'), (4, 'b')",
+                NO_PARAMS,
+            )
+            .unwrap();
+            conn.execute("UPDATE ledger SET id = id + 1 WHERE id = 2", NO_PARAMS)
+                .unwrap();
+            assert_eq!(ids(0, "ledger"), vec![4]);
+            assert_eq!(ids(1, "ledger"), vec![3]);
+        }
+
+        // Delete by equality
+        {
+            conn.execute("DELETE FROM user WHERE ?1 = id", vec![SqlParam::I64(2)])
+                .unwrap();
+            let count: u32 = shard(1)
+                .query_row("SELECT count(*) FROM user WHERE id = 2", [], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+
+        teardown(env);
+    }
+
+    fn shard(index: u32) -> rusqlite::Connection {
+        rusqlite::Connection::open(format!("{}/{}.sqlite", PATH, index)).unwrap()
+    }
+
+    struct Env {
+        pub conn: ShardingIte,
+    }
+
+    fn setup() -> Env {
+        env_logger::init();
+
+        std::fs::remove_dir_all(PATH).ok();
+
+        let conn = ShardingIte::new(ShardingIteConfig {
+            sharding_count: 2,
+            sharding_path: Box::new(|index| format!("{}/{}.sqlite", PATH, index)),
+            sharding_rules: vec![
+                ShardingRule {
+                    table: "user".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+                ShardingRule {
+                    table: "event".to_string(),
+                    columns: vec!["user_id".to_string()],
+                    strategy: ShardingStrategy::Custom(Box::new(|params| match params {
+                        [SqlParam::I64(n)] => Ok((*n as u32 + 1) % 2),
+                        p => Err(format!("Invalid param: {:?}", p).into()),
+                    })),
+                },
+                ShardingRule {
+                    table: "session".to_string(),
+                    columns: vec!["token".to_string()],
+                    strategy: ShardingStrategy::Hash,
+                },
+                ShardingRule {
+                    table: "message".to_string(),
+                    columns: vec!["tenant_id".to_string(), "user_id".to_string()],
+                    strategy: ShardingStrategy::Custom(Box::new(|params| match params {
+                        [SqlParam::I64(t), SqlParam::I64(u)] => Ok(((t + u) % 2) as u32),
+                        p => Err(format!("Invalid params: {:?}", p).into()),
+                    })),
+                },
+                ShardingRule {
+                    table: "metric".to_string(),
+                    columns: vec!["ts".to_string()],
+                    strategy: ShardingStrategy::Range(vec![(0, 0), (100, 1)]),
+                },
+                ShardingRule {
+                    table: "account".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+                ShardingRule {
+                    table: "ledger".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Modulo,
+                },
+                ShardingRule {
+                    table: "item".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Bucket(BucketMap::new(16).unwrap()),
+                },
+                ShardingRule {
+                    table: "visit".to_string(),
+                    columns: vec!["id".to_string()],
+                    strategy: ShardingStrategy::Hash,
+                },
+                ShardingRule {
+                    table: "region".to_string(),
+                    columns: vec!["code".to_string()],
+                    strategy: ShardingStrategy::Lookup(
+                        vec![("eu".to_string(), 0), ("us".to_string(), 1)]
+                            .into_iter()
+                            .collect(),
+                    ),
+                },
+            ],
+            reference_tables: vec!["city".to_string()],
+            key_update: KeyUpdate::Move,
+        })
+        .unwrap();
+
+        conn.execute_batch(
+            r#"
+        CREATE TABLE IF NOT EXISTS user (
+            id INTEGER NOT NULL,
+            name VARCHAR NOT NULL,
+            age INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS city (
+            name VARCHAR NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS event (
+            id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS message (
+            tenant_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            body VARCHAR NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS metric (
+            ts INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS account (
+            id INTEGER PRIMARY KEY,
+            balance INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS ledger (
+            id INTEGER PRIMARY KEY,
+            note VARCHAR NOT NULL
+        ) WITHOUT ROWID;
+
+        CREATE TABLE IF NOT EXISTS staging (
+            id INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS item (
+            id INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS visit (
+            id INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS region (
+            code VARCHAR NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS session (
+            token BLOB,
+            user_id INTEGER NOT NULL
+        );
+        "#,
+        )
+        .unwrap();
+
+        Env { conn }
+    }
+
+    fn teardown(_env: Env) {}
+}